@@ -7,3 +7,4 @@ cargo build --release
 
 cp /mls/target/release/proxy /mls/release/
 cp /mls/target/release/label_db /mls/release/
+cp /mls/target/release/mlsctl /mls/release/
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use log::warn;
+
+use crate::query::QueryTopics;
+use crate::{dominates, may_write, Label};
+
+const HEADER: &str = "Generated by label_db, do not edit";
+
+/// The topics a user with `clearance` may read and if it may also write them, the infos below `mls_topic` are only
+/// published by superusers
+fn accessible<'t>(topics: &'t [(String, Label)], clearance: Label, mls_topic: &'t str) -> impl Iterator<Item = (&'t str, bool)> {
+    topics
+        .iter()
+        .filter(move |(topic, label)| dominates(clearance, *label) && !is_below(topic, mls_topic) && is_renderable(topic))
+        .map(move |(topic, label)| (topic.as_str(), may_write(clearance, *label)))
+}
+
+/// Topics come from the publishers, a line break in one would add its own lines to the rendered ACLs
+fn is_renderable(topic: &str) -> bool {
+    if topic.contains(['\n', '\r']) {
+        warn!("Skipped the topic {topic:?} in the ACLs, it contains a line break");
+        return false;
+    }
+    true
+}
+
+/// Checks if `topic` is `prefix` or a topic below it, like the infos below `mls_topic`
+pub fn is_below(topic: &str, prefix: &str) -> bool {
+    topic.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Renders a mosquitto `acl_file`.
+///
+/// Every user may read the topics its clearance dominates and write those labeled with its clearance,
+/// superusers may read and write everything. With `query` every user may also publish label queries and read
+/// its own replies.
+pub fn mosquitto(
+    topics: &[(String, Label)],
+    clearances: &BTreeMap<String, Label>,
+    superusers: &[String],
+    mls_topic: &str,
+    query: Option<&QueryTopics>,
+) -> String {
+    let mut acl = format!("# {HEADER}\n");
+    for user in superusers {
+        let _ = write!(acl, "\nuser {user}\ntopic readwrite #\n");
+    }
+    for (user, clearance) in clearances {
+        if superusers.contains(user) {
+            continue;
+        }
+        let _ = write!(acl, "\nuser {user}\n");
+        for (topic, writable) in accessible(topics, *clearance, mls_topic) {
+            let access = if writable { "readwrite" } else { "read" };
+            let _ = writeln!(acl, "topic {access} {topic}");
+        }
+        if let Some(query) = query {
+            let _ = write!(acl, "topic write {}\ntopic read {}\n", query.topic, query.replies(user));
+        }
+    }
+    acl
+}
+
+/// Converts an MQTT topic into the routing key the RabbitMQ MQTT plugin uses for it.
+fn routing_key(topic: &str) -> String {
+    topic
+        .chars()
+        .map(|c| match c {
+            '/' => '.',
+            '.' => '/',
+            c => c,
+        })
+        .collect()
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A regex matching exactly the escaped `keys`, or only the empty routing key if there are none
+fn pattern(keys: &[String]) -> String {
+    if keys.is_empty() {
+        "^$".to_string()
+    } else {
+        format!("^({})$", keys.join("|"))
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Renders a shell script setting the RabbitMQ topic permissions of every user.
+///
+/// The read regex of a user only matches the routing keys of topics its clearance dominates,
+/// the write regex only those labeled with its clearance. With `query` they also match the query topic and the
+/// replies of the user.
+pub fn rabbitmq(
+    topics: &[(String, Label)],
+    clearances: &BTreeMap<String, Label>,
+    superusers: &[String],
+    mls_topic: &str,
+    query: Option<&QueryTopics>,
+    vhost: &str,
+    exchange: &str,
+) -> String {
+    let mut script = format!("#!/bin/sh\n# {HEADER}\n");
+    let mut set_permissions = |user: &str, write: &str, read: &str| {
+        let _ = writeln!(
+            script,
+            "rabbitmqctl set_topic_permissions -p {} {} {} {} {}",
+            shell_quote(vhost),
+            shell_quote(user),
+            shell_quote(exchange),
+            shell_quote(write),
+            shell_quote(read)
+        );
+    };
+    for user in superusers {
+        set_permissions(user, ".*", ".*");
+    }
+    for (user, clearance) in clearances {
+        if superusers.contains(user) {
+            continue;
+        }
+        let (mut read, mut write) = (Vec::new(), Vec::new());
+        for (topic, writable) in accessible(topics, *clearance, mls_topic) {
+            let key = regex_escape(&routing_key(topic));
+            if writable {
+                write.push(key.clone());
+            }
+            read.push(key);
+        }
+        if let Some(query) = query {
+            write.push(regex_escape(&routing_key(&query.topic)));
+            read.push(format!(r"{}(\..*)?", regex_escape(&routing_key(&format!("{}{user}", query.reply_prefix)))));
+        }
+        set_permissions(user, &pattern(&write), &pattern(&read));
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Fixture = (Vec<(String, Label)>, BTreeMap<String, Label>, Vec<String>);
+
+    fn fixture() -> Fixture {
+        let topics = vec![("a/b".to_string(), 1), ("a/c.d".to_string(), 3), ("mls/info/a".to_string(), 0)];
+        let clearances = BTreeMap::from([("high".to_string(), 3), ("low".to_string(), 1), ("none".to_string(), 0)]);
+        (topics, clearances, vec!["proxy".to_string()])
+    }
+
+    #[test]
+    fn mosquitto_acl() {
+        let (topics, clearances, superusers) = fixture();
+        let acl = mosquitto(&topics, &clearances, &superusers, "mls/info", None);
+        assert!(acl.contains("user proxy\ntopic readwrite #\n"));
+        assert!(acl.contains("user low\ntopic readwrite a/b\n\n"));
+        // No write down to the lower topic
+        assert!(acl.contains("user high\ntopic read a/b\ntopic readwrite a/c.d\n\n"));
+        assert!(acl.ends_with("user none\n"));
+    }
+
+    #[test]
+    fn hostile_topics() {
+        let (mut topics, clearances, superusers) = fixture();
+        topics.push(("a/x\nuser low\ntopic readwrite #".to_string(), 1));
+        topics.push(("a/y'\r\nrabbitmqctl delete_user proxy".to_string(), 1));
+        let acl = mosquitto(&topics, &clearances, &superusers, "mls/info", None);
+        assert_eq!(acl.matches("topic readwrite #").count(), 1);
+        assert_eq!(acl.lines().filter(|line| line.starts_with("user low")).count(), 1);
+        let script = rabbitmq(&topics, &clearances, &superusers, "mls/info", None, "/", "amq.topic");
+        assert_eq!(script.lines().count(), 6);
+        assert!(!script.contains("delete_user"));
+    }
+
+    #[test]
+    fn rabbitmq_permissions() {
+        let (topics, clearances, superusers) = fixture();
+        let script = rabbitmq(&topics, &clearances, &superusers, "mls/info", None, "/", "amq.topic");
+        assert!(script.contains(r"'low' 'amq.topic' '^(a\.b)$' '^(a\.b)$'"));
+        assert!(script.contains("'none' 'amq.topic' '^$' '^$'"));
+        assert!(script.contains(r"'high' 'amq.topic' '^(a\.c/d)$' '^(a\.b|a\.c/d)$'"));
+        assert!(script.contains("'proxy' 'amq.topic' '.*' '.*'"));
+        assert_eq!(regex_escape(&routing_key("a/c.d")), r"a\.c/d");
+        assert!(is_below("mls/info", "mls/info") && !is_below("mls/infos", "mls/info"));
+    }
+
+    #[test]
+    fn query_topics() {
+        let (topics, clearances, superusers) = fixture();
+        let query = QueryTopics { topic: "mls/query".into(), reply_prefix: "mls/reply/".into() };
+        let acl = mosquitto(&topics, &clearances, &superusers, "mls/info", Some(&query));
+        assert!(acl.contains("user low\ntopic readwrite a/b\ntopic write mls/query\ntopic read mls/reply/low/#\n"));
+        assert!(acl.ends_with("user none\ntopic write mls/query\ntopic read mls/reply/none/#\n"));
+        let script = rabbitmq(&topics, &clearances, &superusers, "mls/info", Some(&query), "/", "amq.topic");
+        assert!(script.contains(r"'low' 'amq.topic' '^(a\.b|mls\.query)$' '^(a\.b|mls\.reply\.low(\..*)?)$'"));
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::Label;
+
+type Blake2b256 = Blake2b<U32>;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("could not access the audit log")]
+    Io(#[from] io::Error),
+    #[error("record {0} is not valid")]
+    Format(u64, #[source] serde_json::Error),
+    #[error("record {0} has the sequence number {1}, records before it were removed")]
+    Sequence(u64, u64),
+    #[error("record {0} does not continue the hash chain, the record before it was modified")]
+    Chain(u64),
+    #[error("the last record is incomplete")]
+    Incomplete,
+    #[error("no record has the hash {0}, the log was truncated or modified")]
+    Anchor(String),
+    #[error("the head file is not valid")]
+    Head,
+}
+
+/// The hex encoded BLAKE2b-256 hash of `bytes`
+pub fn digest(bytes: &[u8]) -> String {
+    Blake2b256::digest(bytes).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// `prev` of the first record
+fn genesis() -> String {
+    "0".repeat(64)
+}
+
+/// Records after which the head is saved again
+const HEAD_RECORDS: u64 = 100;
+/// Time after which the head is saved again with the next record
+const HEAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The file next to the log the head is saved to, `<log>.head`
+pub fn head_path(log: &Path) -> PathBuf {
+    let mut path = log.as_os_str().to_owned();
+    path.push(".head");
+    PathBuf::from(path)
+}
+
+/// Reads the hash saved by `AuditLog`, `None` if the log has no head file
+pub fn read_head(path: &Path) -> Result<Option<String>, AuditError> {
+    match fs::read_to_string(path) {
+        Ok(content) => match content.split_whitespace().collect::<Vec<_>>()[..] {
+            [_records, head] => Ok(Some(head.into())),
+            _ => Err(AuditError::Head),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// The proxy forwarded a message labeled by a topic or content rule
+    Labeled,
+    /// The proxy forwarded a message on an unlabeled topic by the unlabeled policy
+    Unlabeled,
+    /// The proxy dropped a message on an unlabeled topic
+    Dropped,
+    /// label_db stored the label of a signed info
+    Accepted,
+    /// label_db ignored a signed info published for another topic or older than the info it has
+    Ignored,
+    /// label_db rejected an info which failed to verify
+    Rejected,
+    /// label_db stored a label set through its socket
+    Set,
+    /// The log ended with an incomplete record, e.g. after a crash, which was removed
+    Repaired,
+}
+
+impl Decision {
+    /// The name in the log, e.g. to count decisions
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Labeled => "labeled",
+            Decision::Unlabeled => "unlabeled",
+            Decision::Dropped => "dropped",
+            Decision::Accepted => "accepted",
+            Decision::Ignored => "ignored",
+            Decision::Rejected => "rejected",
+            Decision::Set => "set",
+            Decision::Repaired => "repaired",
+        }
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    pub datetime: i64,
+    /// The labeled topic, or the topic a message which failed to verify was received on
+    pub topic: String,
+    pub label: Option<Label>,
+    /// Id of the key which signed the label
+    pub key_id: Option<String>,
+    /// Hash of the message the decision was made on
+    pub digest: String,
+    pub decision: Decision,
+    /// Hash of the previous line, so a modified or removed record breaks the chain
+    pub prev: String,
+}
+
+/// Result of a verified audit log
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verified {
+    pub records: u64,
+    /// Hash of the last line, an earlier head shows that the log was not truncated since
+    pub head: String,
+}
+
+/// Checks the hash chain of an audit log and that it contains the record with the hash `anchor`.
+///
+/// Removing records from the end or modifying the last record keeps the chain intact, only
+/// comparing with a head noted earlier detects it.
+pub fn verify(mut reader: impl BufRead, anchor: Option<&str>) -> Result<Verified, AuditError> {
+    let mut verified = Verified { records: 0, head: genesis() };
+    let mut anchored = anchor.is_none_or(|anchor| anchor == verified.head);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let Some(content) = line.strip_suffix('\n') else {
+            return Err(AuditError::Incomplete);
+        };
+        let record: Record = serde_json::from_str(content).map_err(|e| AuditError::Format(verified.records, e))?;
+        if record.seq != verified.records {
+            return Err(AuditError::Sequence(verified.records, record.seq));
+        }
+        if record.prev != verified.head {
+            return Err(AuditError::Chain(verified.records));
+        }
+        verified.head = digest(content.as_bytes());
+        verified.records += 1;
+        anchored |= anchor == Some(verified.head.as_str());
+        line.clear();
+    }
+    match anchor {
+        Some(anchor) if !anchored => Err(AuditError::Anchor(anchor.into())),
+        _ => Ok(verified),
+    }
+}
+
+/// Cuts the log after its last complete line, returns the bytes which were removed
+fn truncate_incomplete(path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = fs::read(path)?;
+    let complete = content.iter().rposition(|byte| *byte == b'\n').map_or(0, |end| end + 1);
+    let removed = content.split_off(complete);
+    OpenOptions::new().write(true).open(path)?.set_len(complete as u64)?;
+    Ok(removed)
+}
+
+struct Chain {
+    file: File,
+    seq: u64,
+    head: String,
+    /// Records when the head was saved last
+    saved: u64,
+    saved_at: Instant,
+}
+
+/// Appends the labeling decisions of a service to a hash chained log file.
+///
+/// The head is saved to `<log>.head` every `HEAD_RECORDS` records, after `HEAD_INTERVAL` and when the log is closed,
+/// `mlsctl audit` verifies that the log still contains it, so records removed from the end are detected.
+pub struct AuditLog {
+    chain: Mutex<Chain>,
+    head_path: PathBuf,
+}
+
+impl AuditLog {
+    /// Verifies an existing log before continuing its chain.
+    ///
+    /// An incomplete last record, left by a crash while it was written, is removed and the removal recorded.
+    pub fn open(path: &Path) -> Result<Self, AuditError> {
+        let mut incomplete = None;
+        let verified = match File::open(path) {
+            Ok(file) => match verify(BufReader::new(file), None) {
+                Err(AuditError::Incomplete) => {
+                    let removed = truncate_incomplete(path)?;
+                    warn!("Removed the incomplete last record of the audit log {}", path.display());
+                    incomplete = Some(removed);
+                    verify(BufReader::new(File::open(path)?), None)?
+                }
+                verified => verified?,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Verified { records: 0, head: genesis() },
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let audit = AuditLog {
+            chain: Mutex::new(Chain {
+                file,
+                seq: verified.records,
+                head: verified.head,
+                saved: verified.records,
+                saved_at: Instant::now(),
+            }),
+            head_path: head_path(path),
+        };
+        if let Some(removed) = incomplete {
+            audit.record(Decision::Repaired, "", None, None, &removed)?;
+        }
+        Ok(audit)
+    }
+
+    /// Replaces the head file without exposing a half written one
+    fn save_head(&self, chain: &mut Chain) -> io::Result<()> {
+        let tmp_path = self.head_path.with_extension("tmp");
+        fs::write(&tmp_path, format!("{} {}\n", chain.seq, chain.head))?;
+        fs::rename(&tmp_path, &self.head_path)?;
+        chain.saved = chain.seq;
+        chain.saved_at = Instant::now();
+        Ok(())
+    }
+
+    /// The number of records and the hash of the last one
+    pub fn head(&self) -> (u64, String) {
+        let chain = self.chain.lock().unwrap();
+        (chain.seq, chain.head.clone())
+    }
+
+    /// Appends the decision made on `msg`
+    pub fn record(&self, decision: Decision, topic: &str, label: Option<Label>, key_id: Option<&str>, msg: &[u8]) -> Result<(), AuditError> {
+        let mut chain = self.chain.lock().unwrap();
+        let record = Record {
+            seq: chain.seq,
+            datetime: chrono::Utc::now().timestamp(),
+            topic: topic.into(),
+            label,
+            key_id: key_id.map(Into::into),
+            digest: digest(msg),
+            decision,
+            prev: chain.head.clone(),
+        };
+        let mut line = serde_json::to_string(&record).map_err(|e| AuditError::Format(record.seq, e))?;
+        let head = digest(line.as_bytes());
+        line.push('\n');
+        chain.file.write_all(line.as_bytes())?;
+        chain.seq += 1;
+        chain.head = head;
+        if chain.seq - chain.saved >= HEAD_RECORDS || chain.saved_at.elapsed() >= HEAD_INTERVAL {
+            self.save_head(&mut chain)?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends a decision to the audit log if there is one.
+///
+/// A failed write is only logged by both the proxy and label_db, the decision is already made and failing
+/// would only restart the connection the message came from.
+pub fn record_logged(audit: Option<&AuditLog>, decision: Decision, topic: &str, label: Option<Label>, key_id: Option<&str>, msg: &[u8]) {
+    if let Some(audit) = audit {
+        if let Err(e) = audit.record(decision, topic, label, key_id, msg) {
+            error!("Could not record the {decision:?} label of {topic} in the audit log: {e:#}");
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        let mut chain = self.chain.lock().unwrap();
+        if chain.saved != chain.seq {
+            // Nothing to report to at this point, a stale head only misses the last records
+            let _ = self.save_head(&mut chain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(log: &str, anchor: Option<&str>) -> Result<Verified, AuditError> {
+        verify(log.as_bytes(), anchor)
+    }
+
+    #[test]
+    fn hash_chain() {
+        let path = std::env::temp_dir().join(format!("mls_audit_test_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(Decision::Labeled, "sensors/a", Some(2), Some("fog.label"), b"21.5").unwrap();
+        audit.record(Decision::Dropped, "other", None, None, b"x").unwrap();
+        drop(audit);
+        // A reopened log continues the chain
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(Decision::Unlabeled, "mls/quarantine/other", Some(4), Some("fog.label"), b"y").unwrap();
+        let (records, head) = audit.head();
+        drop(audit);
+        // The head is saved when the log is closed
+        assert_eq!(read_head(&head_path(&path)).unwrap(), Some(head.clone()));
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+
+        assert_eq!(check(&log, Some(&head)).unwrap(), Verified { records, head: head.clone() });
+        assert_eq!(records, 3);
+        let lines: Vec<&str> = log.lines().collect();
+        let first: Record = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!((first.seq, first.label, first.digest), (0, Some(2), digest(b"21.5")));
+
+        let modified = log.replacen("\"label\":2", "\"label\":0", 1);
+        assert!(matches!(check(&modified, None), Err(AuditError::Chain(1))));
+        let without_first = lines[1..].join("\n") + "\n";
+        assert!(matches!(check(&without_first, None), Err(AuditError::Sequence(0, 1))));
+        assert!(matches!(check(&log[..log.len() - 5], None), Err(AuditError::Incomplete)));
+        let without_last = lines[..2].join("\n") + "\n";
+        assert!(check(&without_last, None).is_ok());
+        assert!(matches!(check(&without_last, Some(&head)), Err(AuditError::Anchor(_))));
+    }
+
+    #[test]
+    fn repair_incomplete_record() {
+        let path = std::env::temp_dir().join(format!("mls_audit_repair_test_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = AuditLog::open(&path).unwrap();
+        audit.record(Decision::Labeled, "sensors/a", Some(2), Some("fog.label"), b"21.5").unwrap();
+        drop(audit);
+        // A crash in the middle of writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"seq\":1,\"datetime\"").unwrap();
+        drop(file);
+        let audit = AuditLog::open(&path).unwrap();
+        drop(audit);
+        let log = fs::read_to_string(&path).unwrap();
+        assert_eq!(check(&log, None).unwrap().records, 2);
+        let repaired: Record = serde_json::from_str(log.lines().nth(1).unwrap()).unwrap();
+        assert_eq!((repaired.seq, repaired.decision, repaired.digest), (1, Decision::Repaired, digest(b"{\"seq\":1,\"datetime\"")));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn head_saved_periodically() {
+        let path = std::env::temp_dir().join(format!("mls_audit_head_test_{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let audit = AuditLog::open(&path).unwrap();
+        for _ in 0..HEAD_RECORDS - 1 {
+            audit.record(Decision::Labeled, "sensors/a", Some(2), Some("fog.label"), b"21.5").unwrap();
+        }
+        assert_eq!(read_head(&head_path(&path)).unwrap(), None);
+        audit.record(Decision::Labeled, "sensors/a", Some(2), Some("fog.label"), b"21.5").unwrap();
+        let saved = read_head(&head_path(&path)).unwrap().unwrap();
+        assert_eq!(saved, audit.head().1);
+        // Removing records from the end is detected with the saved head
+        let log = fs::read_to_string(&path).unwrap();
+        let truncated = &log[..log[..log.len() - 1].rfind('\n').unwrap() + 1];
+        assert!(matches!(check(truncated, Some(&saved)), Err(AuditError::Anchor(_))));
+        std::mem::forget(audit);
+        fs::write(head_path(&path), "garbage").unwrap();
+        assert!(matches!(read_head(&head_path(&path)), Err(AuditError::Head)));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(head_path(&path)).unwrap();
+    }
+}
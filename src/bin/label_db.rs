@@ -1,11 +1,13 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
-use std::time::SystemTime;
+use std::{collections::BTreeMap, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use std::time::{Instant, SystemTime};
 use std::str::FromStr;
-use std::io;
 use std::fs;
+use std::process::ExitCode;
 
+use clap::Parser;
 use eyre::{eyre, Result};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rumqttc::v5::{self, mqttbytes::v5::{Packet as PacketV5, Publish as PublishV5, PublishProperties}};
 use rumqttc::{
     AsyncClient, ConnectionError,
     Event::{Incoming, Outgoing},
@@ -13,42 +15,141 @@ use rumqttc::{
     Packet,
     QoS,
     Publish,
+    SubscribeFilter,
 };
 use serde::{Deserialize, Serialize};
-use tokio::{net::UnixStream, runtime::Builder, select, task};
+use tokio::{net::UnixStream, runtime::Builder, select, sync::watch, task};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::UnixListener;
 
 
 use mls::{
-    ErrorCounter,
+    reconnect::Reconnect,
+    Label,
+    dominates,
+    may_write,
+    acl,
+    audit::{record_logged, AuditLog, Decision},
+    Key,
+    conf::{self, default_shutdown_timeout_secs, AuditConfig, ConfKey, ConfPubKey, CredentialsConfig, HealthConfig, MetricsConfig, ReconnectConfig, SessionConfig, StatusConfig, TlsConfig},
+    status::{State, StatusAnnouncer},
+    query::{LabelQuery, LabelResponse, QueryTopics},
+    health::Health,
+    http::{self, Request, Response},
+    metrics::{Counter, Gauge, Histogram, Registry, LATENCY_BUCKETS},
     LabeledInfo,
     SignedMsg,
     PublicKey,
-    topicdb::Database,
+    topicdb::{self, Database},
     topicdb::DBResult,
+    topicdb::RequestError,
 };
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Clearances {
+    /// Clearance of each broker user
+    #[serde(default)]
+    users: BTreeMap<String, Label>,
+    /// Users which may access every topic, e.g. the proxy and label_db itself
+    #[serde(default)]
+    superusers: Vec<String>,
+}
+
+impl Clearances {
+    fn is_superuser(&self, username: &str) -> bool {
+        self.superusers.iter().any(|user| user == username)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct ConfPubKey {
-    key: String,
-    id: String
-}
-
-impl ConfPubKey {
-    fn get_key(&self) -> Result<PublicKey> {
-        let ssh_pubkey = ssh_key::PublicKey::from_openssh(&self.key)?;
-        let pub_key = ssh_pubkey.key_data();
-        let pub_key = match pub_key {
-            ssh_key::public::KeyData::Ed25519(key_data) => {
-                key_data.clone().try_into()?
-            }
-            _ => {
-                return Err(eyre!("Key is not an Ed25519"))
-            }
-        };
-        Ok(PublicKey::new(
-            pub_key,
-        ))
+struct AuthConfig {
+    /// Address of the HTTP endpoint the broker auth plugin calls
+    listen: SocketAddr,
+    /// Allow subscriptions to filters which do not match any labeled topic and publishing to unlabeled topics
+    #[serde(default)]
+    allow_unlabeled: bool,
+    /// Answer every `/vhost` and `/resource` request of the RabbitMQ HTTP backend with allow, the hook only checks
+    /// topics, so the vhost and resource permissions then have to be set in RabbitMQ itself. Without it only the
+    /// superusers are allowed
+    #[serde(default)]
+    allow_vhosts: bool,
+}
+
+fn default_vhost() -> String {
+    "/".into()
+}
+
+fn default_exchange() -> String {
+    "amq.topic".into()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AclConfig {
+    /// Path of the generated mosquitto acl_file
+    mosquitto: Option<PathBuf>,
+    /// Path of the generated script setting the RabbitMQ topic permissions
+    rabbitmq: Option<PathBuf>,
+    #[serde(default = "default_vhost")]
+    vhost: String,
+    #[serde(default = "default_exchange")]
+    exchange: String,
+    /// Shell command run after the files were regenerated, e.g. to reload the broker
+    reload_command: Option<String>,
+}
+
+/// Label queries over the broker.
+///
+/// A query is answered on the replies of the user its reply topic names, with the label only if the clearance of that
+/// user dominates it, the auth hook and the generated ACLs let only the user read its replies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueryConfig {
+    #[serde(flatten)]
+    topics: QueryTopics,
+    /// Key signing the replies
+    key: ConfKey,
+    /// Broker url of a separate MQTT v5 connection answering the queries, which takes the reply topic and
+    /// correlation data from the v5 properties of a query and falls back to the payload without them
+    #[serde(default)]
+    broker_v5: Option<String>,
+}
+
+struct QueryResponder {
+    topics: QueryTopics,
+    key: Key,
+    clearances: Arc<Clearances>,
+}
+
+/// Counters of label_db, collected even without a metrics endpoint
+struct Metrics {
+    registry: Registry,
+    signature_failures: Arc<Counter>,
+    reconnects: Arc<Counter>,
+    queries: Arc<Counter>,
+    topics: Arc<Gauge>,
+    sign_seconds: Arc<Histogram>,
+    lookup_seconds: Arc<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::default();
+        Metrics {
+            signature_failures: registry.counter("mls_label_db_signature_failures_total", "Infos with a signature which failed to verify", &["key_id"]),
+            reconnects: registry.counter("mls_label_db_reconnects_total", "Reconnects of the broker connection", &["broker"]),
+            queries: registry.counter("mls_label_db_socket_queries_total", "Queries on the socket by command and result", &["command", "result"]),
+            topics: registry.gauge("mls_label_db_topics", "Labeled topics in the database", &[]),
+            sign_seconds: registry.histogram("mls_label_db_sign_seconds", "Seconds to sign a label query response", &[], LATENCY_BUCKETS),
+            lookup_seconds: registry.histogram("mls_label_db_lookup_seconds", "Seconds to look up labels by request", &["request"], LATENCY_BUCKETS),
+            registry,
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a socket query with the seconds its lookup took since `start`
+    fn query(&self, command: &str, result: &str, start: Instant) {
+        self.queries.inc(&[command, result]);
+        self.lookup_seconds.observe_since(&[command], start);
     }
 }
 
@@ -57,9 +158,50 @@ struct Config {
     broker: String,
     log_level: String,
     mls_topic: String,
+    /// Subscription QoS of the info topics
+    #[serde(default)]
+    mls_qos: u8,
     mls_pubkey: ConfPubKey,
+    /// Session of the broker connection
+    #[serde(default)]
+    session: SessionConfig,
+    /// Backoff and error budget of the broker connection
+    #[serde(default)]
+    reconnect: ReconnectConfig,
+    /// Liveness of label_db on the broker
+    #[serde(default)]
+    status: Option<StatusConfig>,
+    /// Seconds to disconnect from the broker after SIGINT or SIGTERM
+    #[serde(default = "default_shutdown_timeout_secs")]
+    shutdown_timeout_secs: u64,
+    /// Hash chained log of the labels accepted, ignored, rejected or set through the socket
+    #[serde(default)]
+    audit: Option<AuditConfig>,
+    /// Prometheus endpoint
+    #[serde(default)]
+    metrics: Option<MetricsConfig>,
+    /// Liveness and readiness endpoint, ready once connected, the retained infos are loaded and listening on the socket
+    #[serde(default)]
+    health: Option<HealthConfig>,
+    /// TLS of the broker connection
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// Credentials of the broker connection, `auth` configures the endpoint of the broker auth plugin
+    #[serde(default)]
+    broker_auth: Option<CredentialsConfig>,
     threads: usize,
     socket_path: PathBuf,
+    /// Lets anyone with access to the socket overwrite labels with `SET`, e.g. for `mlsctl import`
+    #[serde(default)]
+    allow_set: bool,
+    #[serde(default)]
+    clearances: Clearances,
+    #[serde(default)]
+    auth: Option<AuthConfig>,
+    #[serde(default)]
+    acl: Option<AclConfig>,
+    #[serde(default)]
+    query: Option<QueryConfig>,
 }
 
 impl ::std::default::Default for Config {
@@ -68,12 +210,27 @@ impl ::std::default::Default for Config {
             broker: "mqtt://localhost:1883?client_id=label_db1".into(),
             log_level: "info".into(),
             mls_topic: "mls/info".into(),
+            mls_qos: 0,
             mls_pubkey: ConfPubKey{
                 key: "<type> <public_key>[<comment>]".into(),
                 id: "proxy.info.1".into(),
             },
+            session: SessionConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            status: None,
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            audit: None,
+            metrics: None,
+            health: None,
+            tls: None,
+            broker_auth: None,
             threads: 2,
             socket_path: "/tmp/mls/labeldb.sock".into(),
+            allow_set: false,
+            clearances: Clearances::default(),
+            auth: None,
+            acl: None,
+            query: None,
         }
     }
 }
@@ -95,8 +252,23 @@ fn setup_logger(level: &str) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// Stores the labels the proxies announce and answers label queries
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// ask the health endpoint of the running label_db if it is ready, exits with 1 if not
+    #[arg(long)]
+    healthcheck: bool,
+}
+
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
     let cfg: Config = confy::load("mls", "labeldb.conf")?;
+    if args.healthcheck {
+        let health = cfg.health.ok_or_else(|| eyre!("The health check needs the health endpoint in the config"))?;
+        let ready = mls::health::check(health.listen, Duration::from_secs(5))?;
+        return Ok(if ready { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
     let parent_dir = cfg.socket_path.parent().unwrap();
     fs::create_dir_all(parent_dir)?;
     if let Err(e) = fs::remove_file(&cfg.socket_path) {
@@ -110,87 +282,236 @@ fn main() -> Result<()> {
         .worker_threads(cfg.threads)
         .build()?
         .block_on(async move { main_loop(cfg).await })?;
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
 async fn main_loop(cfg: Config) -> Result<()> {
     let (db, db_handle) = Database::new();
+    let metrics = Arc::new(Metrics::default());
+    let health = Arc::new(Health::default());
     let verify_key = Arc::new(cfg.mls_pubkey.get_key()?);
-    let broker_handle = task::spawn(broker_task(cfg.broker.clone(), cfg.mls_topic.clone(), verify_key.clone(), db.clone()));
-    let socket_handle = task::spawn(socket_task(cfg.socket_path.clone(), db.clone()));
+    let mut mqttoptions = mqtt_options(&cfg)?;
+    let status = match &cfg.status {
+        Some(status) => {
+            let key_ids = std::iter::once(&status.key.id).chain(cfg.query.as_ref().map(|query| &query.key.id)).cloned().collect();
+            let status = StatusAnnouncer::new(status, status.key.get_key()?, "label_db", mqttoptions.client_id(), key_ids);
+            status.apply(&mut mqttoptions)?;
+            Some(status)
+        }
+        None => None,
+    };
+    let query_options_v5 = match cfg.query.as_ref().and_then(|query| query.broker_v5.as_ref()) {
+        Some(url) => Some(query_mqtt_options_v5(&cfg, url)?),
+        None => None,
+    };
+    let clearances = Arc::new(cfg.clearances.clone());
+    let query_topics = cfg.query.as_ref().map(|query| query.topics.clone());
+    if let Some(topics) = &query_topics {
+        if !topics.reply_prefix.ends_with('/') {
+            return Err(eyre!("The reply_prefix {} does not end with /", topics.reply_prefix));
+        }
+    }
+    let query = match &cfg.query {
+        Some(query) => Some(Arc::new(QueryResponder {
+            key: query.key.get_key()?,
+            topics: query.topics.clone(),
+            clearances: clearances.clone(),
+        })),
+        None => None,
+    };
+    // Queries are answered on the v5 connection instead of the info connection if there is one
+    let (query, query_v5) = match query_options_v5 {
+        Some(_) => (None, query),
+        None => (query, None),
+    };
+    let audit = match &cfg.audit {
+        Some(audit) => {
+            let log = AuditLog::open(&audit.path)?;
+            let (records, head) = log.head();
+            info!("Audit log {} continues after {records} records at {head}", audit.path.display());
+            Some(Arc::new(log))
+        }
+        None => None,
+    };
+    let mls_qos = rumqttc::qos(cfg.mls_qos).map_err(|_| eyre!("The mls_qos {} is not valid", cfg.mls_qos))?;
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let reconnect = Reconnect::new(cfg.reconnect.clone()).with_metric(metrics.reconnects.clone(), "broker");
+    let (connected, loaded) = (health.part("broker"), health.part("database"));
+    let query_reconnect = Reconnect::new(cfg.reconnect.clone()).with_metric(metrics.reconnects.clone(), "query");
+    let mut query_handle = task::spawn(query_v5_task(query_options_v5, query_reconnect, db.clone(), query_v5, metrics.clone(), health.part("query"), shutdown.clone()));
+    let mut broker_handle = task::spawn(broker_task(mqttoptions, reconnect, cfg.mls_topic.clone(), mls_qos, verify_key.clone(), db.clone(), query, audit.clone(), metrics.clone(), status, connected, loaded, shutdown));
+    let mut socket_handle = task::spawn(socket_task(cfg.socket_path.clone(), db.clone(), cfg.allow_set, audit, metrics.clone(), health.part("socket")));
+    let mut auth_handle = task::spawn(auth_task(cfg.auth.clone(), clearances.clone(), db.clone(), metrics.clone(), cfg.mls_topic.clone(), query_topics.clone()));
+    let mut acl_handle = task::spawn(acl_task(cfg.acl.clone(), clearances, db.clone(), cfg.mls_topic.clone(), query_topics));
+    let mut metrics_handle = task::spawn(metrics_task(cfg.metrics.clone(), metrics, db.clone()));
+    let mut health_handle = task::spawn(mls::health::health_task(cfg.health.clone(), health.clone()));
     select! {
-        e = broker_handle => {
+        e = &mut broker_handle => {
+            e??;
+        },
+        e = &mut query_handle => {
+            e??;
+        },
+        e = &mut socket_handle => {
+            e??;
+        },
+        e = &mut auth_handle => {
+            e??;
+        },
+        e = &mut acl_handle => {
             e??;
         },
-        e = socket_handle => {
+        e = &mut metrics_handle => {
+            e??;
+        },
+        e = &mut health_handle => {
             e??;
         },
         e = db_handle => {
             e?;
         }
+        signal = mls::shutdown::signal() => {
+            info!("Received {}, shutting down", signal?);
+            health.stop();
+            // Aborting the tasks drops their listeners, the ACL files are replaced atomically
+            socket_handle.abort();
+            auth_handle.abort();
+            acl_handle.abort();
+            metrics_handle.abort();
+            health_handle.abort();
+            if let Err(e) = fs::remove_file(&cfg.socket_path) {
+                warn!("Could not remove the socket {}: {e}", cfg.socket_path.display());
+            }
+            shutdown_tx.send_replace(true);
+            let timeout = Duration::from_secs(cfg.shutdown_timeout_secs);
+            let disconnected = async {
+                broker_handle.await??;
+                query_handle.await?
+            };
+            match tokio::time::timeout(timeout, disconnected).await {
+                Ok(e) => e?,
+                Err(_) => return Err(eyre!("Shutdown timed out after {}s", cfg.shutdown_timeout_secs)),
+            }
+        }
     }
     Ok(())
 }
 
-async fn handle_get(topic:&[u8], db: &Database, stream: &UnixStream) -> Result<()>{
-    let topic = std::str::from_utf8(topic)?;
+async fn handle_get(topic: &str, db: &Database, metrics: &Metrics) -> Result<String> {
+    let start = Instant::now();
     let label = db.get(topic.to_string()).await?;
-    let lable_str;
-    let reply = match label {
-        DBResult::None => "None",
-        DBResult::Some(label) =>{
-            lable_str = label.to_string();
-            &lable_str
-        },
-        DBResult::Denied(_) => "Denied"
+    metrics.query("get", label.kind(), start);
+    Ok(format!("{label}\n"))
+}
+
+async fn handle_list(filter: &str, db: &Database, metrics: &Metrics) -> Result<String> {
+    let start = Instant::now();
+    let topics = db.list(filter.to_string()).await?;
+    let result = match &topics {
+        Ok(topics) if topics.is_empty() => "None",
+        Ok(_) => "Some",
+        Err(_) => "Denied",
     };
-    stream.try_write(&reply.as_bytes())?;
-    stream.try_write(&"\n".as_bytes())?;
-    Ok(())
+    metrics.query("list", result, start);
+    let reply = match topics {
+        Ok(topics) => {
+            let mut reply = String::new();
+            for (topic, label) in topics {
+                reply.push_str(&format!("{label} {topic}\n"));
+            }
+            reply.push_str("END\n");
+            reply
+        }
+        Err(e) => format!("{}\n", DBResult::Denied(e)),
+    };
+    Ok(reply)
 }
 
-async fn handle_request(stream: UnixStream, db: Database) -> Result<()> {
+async fn handle_check(args: &str, db: &Database, metrics: &Metrics) -> Result<String> {
+    let invalid = format!("{}\n", DBResult::Denied(RequestError::InvalidRequest));
+    let Some((clearance, filter)) = args.split_once(' ') else {
+        return Ok(invalid);
+    };
+    let Ok(clearance) = clearance.parse() else {
+        return Ok(invalid);
+    };
+    let start = Instant::now();
+    let label = db.check(filter.to_string(), clearance).await?;
+    metrics.query("check", label.kind(), start);
+    Ok(format!("{label}\n"))
+}
+
+async fn handle_set(args: &str, db: &Database, allow_set: bool, audit: Option<&AuditLog>) -> Result<String> {
+    if !allow_set {
+        warn!("Denied SET {args}, allow_set is disabled");
+        return Ok(format!("{}\n", DBResult::Denied(RequestError::Forbidden)));
+    }
+    let invalid = format!("{}\n", DBResult::Denied(RequestError::InvalidRequest));
+    let Some((label, topic)) = args.split_once(' ') else {
+        return Ok(invalid);
+    };
+    if topic.contains(['+', '#']) {
+        return Ok(format!("{}\n", DBResult::Denied(RequestError::InvalidTopic)));
+    }
+    let Ok(label) = label.parse() else {
+        return Ok(invalid);
+    };
+    db.insert(topic.to_string(), label).await?;
+    record_logged(audit, Decision::Set, topic, Some(label), None, args.as_bytes());
+    Ok("OK\n".into())
+}
+
+async fn handle_stats(db: &Database) -> Result<String> {
+    let stats = db.stats().await?;
+    Ok(format!("{} topics\n{} inserts\n{} changes\nEND\n", stats.topics, stats.inserts, stats.changes))
+}
+
+/// A command with its arguments, MQTT topics have at most 65535 bytes
+const MAX_REQUEST: usize = 65535 + 64;
+
+async fn handle_request(stream: UnixStream, db: Database, allow_set: bool, audit: Option<Arc<AuditLog>>, metrics: Arc<Metrics>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
     loop {
-        // Wait for the socket to be readable
-        stream.readable().await?;
-
-        // Creating the buffer **after** the `await` prevents it from
-        // being stored in the async task.
-        let mut cmd_buf = [0; 4+65535+1];
-
-        // Try to read data, this may still fail with `WouldBlock`
-        // if the readiness event is a false positive.
-        match stream.try_read(&mut cmd_buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                if &cmd_buf[0..4] == b"GET " {
-                    let topic = &cmd_buf[4..n-1];
-                    handle_get(&topic, &db, &stream).await?;
-                } else {
-                    error!("Unknown command");
-                    return Err(eyre!("Unknown command"))
-                }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                continue;
-            }
-            Err(e) => {
-                error!("Error");
-                return Err(e.into());
-            }
+        line.clear();
+        // A line without end is not buffered beyond the limit
+        let len = (&mut reader).take(MAX_REQUEST as u64).read_line(&mut line).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        if len == MAX_REQUEST && !line.ends_with('\n') {
+            writer.write_all(format!("{}\n", DBResult::Denied(RequestError::InvalidRequest)).as_bytes()).await?;
+            return Err(eyre!("Request longer than {MAX_REQUEST} bytes"));
         }
+        let line = line.trim_end_matches(['\n', '\r']);
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let reply = match cmd {
+            "GET" => handle_get(args, &db, &metrics).await?,
+            "LIST" => handle_list(args, &db, &metrics).await?,
+            "CHECK" => handle_check(args, &db, &metrics).await?,
+            "SET" => handle_set(args, &db, allow_set, audit.as_deref()).await?,
+            "STATS" => handle_stats(&db).await?,
+            _ => {
+                warn!("Unknown command {cmd}");
+                format!("{}\n", DBResult::Denied(RequestError::InvalidRequest))
+            }
+        };
+        writer.write_all(reply.as_bytes()).await?;
     }
-    Ok(())
 }
 
-async fn socket_task(path: PathBuf, db:Database) -> Result<()> {
+async fn socket_task(path: PathBuf, db:Database, allow_set: bool, audit: Option<Arc<AuditLog>>, metrics: Arc<Metrics>, bound: watch::Sender<bool>) -> Result<()> {
     let listener = UnixListener::bind(path)?;
+    bound.send_replace(true);
     loop {
         let db_clone = db.clone();
+        let audit = audit.clone();
+        let metrics = metrics.clone();
         match listener.accept().await {
             Ok((stream, _addr)) => {
                 task::spawn(async move {
-                    match handle_request(stream, db_clone).await {
+                    match handle_request(stream, db_clone, allow_set, audit, metrics).await {
                         Ok(()) => {
                         },
                         Err(e) => {
@@ -206,27 +527,285 @@ async fn socket_task(path: PathBuf, db:Database) -> Result<()> {
     }
 }
 
-async fn handle_topic_info(db:Database, verify_key:Arc<PublicKey>, msg: Publish) -> Result<()> {
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+    /// Checked as both a read and a write
+    ReadWrite,
+}
+
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::ReadWrite => write!(f, "readwrite"),
+        }
+    }
+}
+
+/// Converts an AMQP routing key of the RabbitMQ MQTT plugin back into an MQTT topic filter.
+fn routing_key_to_topic(routing_key: &str) -> String {
+    routing_key
+        .chars()
+        .map(|c| match c {
+            '.' => '/',
+            '/' => '.',
+            '*' => '+',
+            c => c,
+        })
+        .collect()
+}
+
+/// Extracts user, topic filter and access of the request of a broker auth plugin.
+///
+/// `/acl` is called by mosquitto-go-auth (`acc`) and EMQX (`action`),
+/// `/topic` by the RabbitMQ HTTP auth backend.
+fn parse_acl_request(req: &Request) -> Option<(String, String, Access)> {
+    let username = req.param("username")?.to_string();
+    match req.path.as_str() {
+        "/acl" => {
+            let access = match (req.param("acc"), req.param("action")) {
+                (Some("1" | "4"), _) | (_, Some("subscribe")) => Access::Read,
+                (Some("3"), _) => Access::ReadWrite,
+                (Some("2"), _) | (_, Some("publish")) => Access::Write,
+                _ => return None,
+            };
+            Some((username, req.param("topic")?.to_string(), access))
+        }
+        "/topic" => {
+            let access = match req.param("permission")? {
+                "read" => Access::Read,
+                "write" => Access::Write,
+                _ => return None,
+            };
+            Some((username, routing_key_to_topic(req.param("routing_key")?), access))
+        }
+        _ => None,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn is_authorized(auth: &AuthConfig, clearances: &Clearances, db: &Database, metrics: &Metrics, mls_topic: &str, query: Option<&QueryTopics>, username: &str, filter: &str, access: Access) -> Result<bool> {
+    let accesses = match access {
+        Access::ReadWrite => vec![Access::Read, Access::Write],
+        access => vec![access],
+    };
+    for access in accesses {
+        if !check_access(auth, clearances, db, metrics, mls_topic, query, username, filter, access).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Checks a read or a write, a user may only write to topics labeled with its clearance so nothing is written down
+#[allow(clippy::too_many_arguments)]
+async fn check_access(auth: &AuthConfig, clearances: &Clearances, db: &Database, metrics: &Metrics, mls_topic: &str, query: Option<&QueryTopics>, username: &str, filter: &str, access: Access) -> Result<bool> {
+    if clearances.is_superuser(username) {
+        return Ok(true);
+    }
+    // Infos are only published by the proxies, which are superusers
+    if access != Access::Read && acl::is_below(filter, mls_topic) {
+        info!("Denied {username} to write {filter}: infos are only published by the proxies");
+        return Ok(false);
+    }
+    let Some(clearance) = clearances.users.get(username) else {
+        info!("Denied {username} to {access} {filter}: no clearance");
+        return Ok(false);
+    };
+    if let Some(query) = query {
+        // The replies are only published by label_db and only read by the user the reply topic names
+        if topicdb::overlaps(filter, &format!("{}#", query.reply_prefix)) {
+            let allowed = access == Access::Read && topicdb::matches(&query.replies(username), filter);
+            if !allowed {
+                info!("Denied {username} to {access} {filter}: replies of other users");
+            }
+            return Ok(allowed);
+        }
+        if access == Access::Write && filter == query.topic {
+            return Ok(true);
+        }
+    }
+    let start = Instant::now();
+    // A subscription must be allowed to read every topic it matches, a publish only its own topic
+    let result = match access {
+        Access::Read => db.check(filter.to_string(), *clearance).await?,
+        Access::Write | Access::ReadWrite => match db.get(filter.to_string()).await? {
+            DBResult::Some(label) if !may_write(*clearance, label) => DBResult::Denied(RequestError::InsufficientClearance),
+            result => result,
+        },
+    };
+    metrics.lookup_seconds.observe_since(&["auth"], start);
+    let allowed = match result {
+        DBResult::Some(_) => true,
+        DBResult::None => {
+            if !auth.allow_unlabeled {
+                info!("Denied {username} to {access} {filter}: no labeled topic matches");
+            }
+            return Ok(auth.allow_unlabeled);
+        }
+        DBResult::Denied(_) => false,
+    };
+    if !allowed {
+        info!("Denied {username} with clearance {clearance} to {access} {filter}");
+    }
+    Ok(allowed)
+}
+
+async fn handle_auth(req: Request, auth: Arc<AuthConfig>, clearances: Arc<Clearances>, db: Database, metrics: Arc<Metrics>, mls_topic: Arc<String>, query: Arc<Option<QueryTopics>>) -> Response {
+    let allowed = match req.path.as_str() {
+        "/acl" | "/topic" => match parse_acl_request(&req) {
+            Some((username, filter, access)) => is_authorized(&auth, &clearances, &db, &metrics, &mls_topic, query.as_ref().as_ref(), &username, &filter, access).await,
+            None => return Response::new(400, "deny"),
+        },
+        "/superuser" => Ok(req.param("username").is_some_and(|user| clearances.is_superuser(user))),
+        "/vhost" | "/resource" => Ok(auth.allow_vhosts || req.param("username").is_some_and(|user| clearances.is_superuser(user))),
+        _ => return Response::not_found(),
+    };
+    match allowed {
+        Ok(true) => Response::ok("allow"),
+        Ok(false) => Response::new(403, "deny"),
+        Err(e) => {
+            error!("Authorization failed {e:?}");
+            Response::new(503, "deny")
+        }
+    }
+}
+
+async fn auth_task(auth: Option<AuthConfig>, clearances: Arc<Clearances>, db: Database, metrics: Arc<Metrics>, mls_topic: String, query: Option<QueryTopics>) -> Result<()> {
+    let Some(auth) = auth else {
+        return std::future::pending().await;
+    };
+    info!("auth endpoint = {}", auth.listen);
+    let listen = auth.listen;
+    let auth = Arc::new(auth);
+    let (mls_topic, query) = (Arc::new(mls_topic), Arc::new(query));
+    http::serve(listen, move |req| handle_auth(req, auth.clone(), clearances.clone(), db.clone(), metrics.clone(), mls_topic.clone(), query.clone())).await?;
+    Ok(())
+}
+
+async fn handle_metrics(req: Request, metrics: Arc<Metrics>, db: Database) -> Response {
+    if req.path != "/metrics" {
+        return Response::not_found();
+    }
+    match db.stats().await {
+        Ok(stats) => metrics.topics.set(&[], stats.topics as f64),
+        Err(e) => error!("Reading the database statistics failed {e:?}"),
+    }
+    metrics.registry.response()
+}
+
+/// Serves `/metrics` for Prometheus
+async fn metrics_task(cfg: Option<MetricsConfig>, metrics: Arc<Metrics>, db: Database) -> Result<()> {
+    let Some(cfg) = cfg else {
+        return std::future::pending().await;
+    };
+    info!("metrics endpoint = {}", cfg.listen);
+    http::serve(cfg.listen, move |req| handle_metrics(req, metrics.clone(), db.clone())).await?;
+    Ok(())
+}
+
+/// Replaces the file at `path` without exposing a half written file to the broker.
+fn write_atomic(path: &Path, content: &str, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+async fn write_acls(acl_cfg: &AclConfig, clearances: &Clearances, db: &Database, mls_topic: &str, query: Option<&QueryTopics>) -> Result<()> {
+    let topics = db.list("#".into()).await?.map_err(|e| eyre!("Listing topics failed {e}"))?;
+    if let Some(path) = &acl_cfg.mosquitto {
+        write_atomic(path, &acl::mosquitto(&topics, &clearances.users, &clearances.superusers, mls_topic, query), 0o644)?;
+    }
+    if let Some(path) = &acl_cfg.rabbitmq {
+        let script = acl::rabbitmq(&topics, &clearances.users, &clearances.superusers, mls_topic, query, &acl_cfg.vhost, &acl_cfg.exchange);
+        write_atomic(path, &script, 0o755)?;
+    }
+    info!("Regenerated ACLs for {} topics", topics.len());
+    if let Some(cmd) = &acl_cfg.reload_command {
+        let status = tokio::process::Command::new("sh").arg("-c").arg(cmd).status().await?;
+        if !status.success() {
+            error!("ACL reload command failed with {status}");
+        }
+    }
+    Ok(())
+}
+
+const ACL_RETRY: Duration = Duration::from_secs(30);
+
+/// Regenerates the ACLs on every change, a failed write is retried on the next change or after `ACL_RETRY`
+async fn acl_task(acl_cfg: Option<AclConfig>, clearances: Arc<Clearances>, db: Database, mls_topic: String, query: Option<QueryTopics>) -> Result<()> {
+    let Some(acl_cfg) = acl_cfg else {
+        return std::future::pending().await;
+    };
+    let mut changes = db.changes();
+    loop {
+        let failed = match write_acls(&acl_cfg, &clearances, &db, &mls_topic, query.as_ref()).await {
+            Ok(()) => false,
+            Err(e) => {
+                error!("Writing the ACLs failed, retrying in {}s: {e:#}", ACL_RETRY.as_secs());
+                true
+            }
+        };
+        select! {
+            changed = changes.changed() => changed?,
+            _ = tokio::time::sleep(ACL_RETRY), if failed => continue,
+        }
+        // Collect a burst of new labels into a single regeneration
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        changes.borrow_and_update();
+    }
+}
+
+async fn handle_topic_info(db:Database, verify_key:Arc<PublicKey>, mls_topic: Arc<str>, audit: Option<Arc<AuditLog>>, metrics: Arc<Metrics>, msg: Publish) -> Result<()> {
     debug!("Processing Incoming message = {:?}", msg);
+    let audit = audit.as_deref();
+    if msg.payload.is_empty() {
+        // The proxy clears the retained info of a topic it stopped labeling, the label stays until a newer info
+        debug!("Ignored the cleared info on {}", msg.topic);
+        return Ok(());
+    }
     match ciborium::de::from_reader::<SignedMsg, &[u8]>(&msg.payload[..]){
         Err(e) => {
-            error!("Error = {e}")
+            error!("Error = {e}");
+            record_logged(audit, Decision::Rejected, &msg.topic, None, None, &msg.payload);
         },
-        Ok(msg) => {
-            let signed_msg = match msg.verify(&verify_key){
+        Ok(signed) => {
+            let key_id = Some(signed.get_key_id());
+            let signed_msg = match signed.verify(&verify_key){
                 Ok(verified_msg) => verified_msg,
                 Err(e) => {
                     error!("Signature verification failed. Error = {e}");
+                    metrics.signature_failures.inc(&[signed.get_key_id()]);
+                    record_logged(audit, Decision::Rejected, &msg.topic, None, key_id, &msg.payload);
                     return Err(e.into());
                 }
             };
             match ciborium::de::from_reader::<LabeledInfo, &[u8]>(signed_msg) {
                 Err(e) => {
-                    error!("Error = {e}")
+                    error!("Error = {e}");
+                    record_logged(audit, Decision::Rejected, &msg.topic, None, key_id, &msg.payload);
+                },
+                Ok(topic_info) if !topic_info.matches_info_topic(&mls_topic, &msg.topic) => {
+                    warn!("Ignored info for {} published on {}", topic_info.topic, msg.topic);
+                    record_logged(audit, Decision::Ignored, &topic_info.topic, Some(topic_info.label), key_id, &msg.payload);
                 },
                 Ok(topic_info) => {
                     debug!("Inserting {topic_info:?}");
-                    db.insert(topic_info.topic, topic_info.label).await?;
+                    if db.insert_info(topic_info.topic.clone(), topic_info.label, signed.get_datetime()).await? {
+                        record_logged(audit, Decision::Accepted, &topic_info.topic, Some(topic_info.label), key_id, &msg.payload);
+                    } else {
+                        warn!("Ignored info for {} signed at {}, label_db has a newer one", topic_info.topic, signed.get_datetime());
+                        record_logged(audit, Decision::Ignored, &topic_info.topic, Some(topic_info.label), key_id, &msg.payload);
+                    }
                 }
             }
         },
@@ -234,53 +813,567 @@ async fn handle_topic_info(db:Database, verify_key:Arc<PublicKey>, msg: Publish)
     Ok(())
 }
 
+/// Looks up and signs the answer to a query, `None` if its reply topic does not name a user with a clearance.
+///
+/// Only the user named by the reply topic reads the answer, it is denied unless the clearance of the user dominates
+/// the label.
+async fn answer_query(db: &Database, responder: &QueryResponder, metrics: &Metrics, query: LabelQuery) -> Result<Option<Vec<u8>>> {
+    let Some(requester) = responder.topics.reply_owner(&query.reply_topic) else {
+        error!("Ignoring query with reply topic {} outside of {}", query.reply_topic, responder.topics.reply_prefix);
+        return Ok(None);
+    };
+    let clearances = &responder.clearances;
+    let clearance = match clearances.users.get(requester) {
+        _ if clearances.is_superuser(requester) => None,
+        Some(clearance) => Some(*clearance),
+        None => {
+            info!("Ignoring query of {requester} without a clearance");
+            return Ok(None);
+        }
+    };
+    let start = Instant::now();
+    let result = match db.get(query.topic.clone()).await? {
+        DBResult::Some(label) if clearance.is_some_and(|clearance| !dominates(clearance, label)) => DBResult::Denied(RequestError::InsufficientClearance),
+        result => result,
+    };
+    metrics.lookup_seconds.observe_since(&["query"], start);
+    let response = LabelResponse::new(query, result);
+    let response = response.serialize()?;
+    let signed_response = metrics.sign_seconds.time(&[], || responder.key.sign(response));
+    let mut buffer: Vec<u8> = Vec::with_capacity(4098);
+    ciborium::ser::into_writer(&signed_response, &mut buffer)?;
+    Ok(Some(buffer))
+}
+
+async fn handle_query(db: Database, responder: Arc<QueryResponder>, client: AsyncClient, metrics: Arc<Metrics>, msg: Publish) -> Result<()> {
+    debug!("Processing label query = {:?}", msg);
+    let query = match LabelQuery::deserialize(&msg.payload[..]) {
+        Ok(query) => query,
+        Err(e) => {
+            error!("Error = {e}");
+            return Ok(());
+        }
+    };
+    let reply_topic = query.reply_topic.clone();
+    if let Some(reply) = answer_query(&db, &responder, &metrics, query).await? {
+        client.publish(reply_topic, QoS::AtLeastOnce, false, reply).await?;
+    }
+    Ok(())
+}
 
-async fn broker_task(broker: String, topic: String, verify_key: Arc<PublicKey>, db: Database) -> Result<()> {
-    let mut error_broker = ErrorCounter::new();
-    info!("broker = {}", broker);
-    let mut broker_mqttoptions = MqttOptions::parse_url(broker)?;
-    broker_mqttoptions.set_keep_alive(Duration::from_secs(30));
+/// A v5 query with a response topic has the queried topic as payload, others carry a `LabelQuery`
+fn query_v5(msg: &PublishV5) -> Result<LabelQuery> {
+    match &msg.properties {
+        Some(PublishProperties { response_topic: Some(reply_topic), correlation_data, .. }) => {
+            let topic = std::str::from_utf8(&msg.payload)?;
+            Ok(LabelQuery::new(topic, reply_topic, correlation_data.as_ref().map(|data| data.to_vec()).unwrap_or_default()))
+        }
+        _ => Ok(LabelQuery::deserialize(&msg.payload[..])?),
+    }
+}
 
+async fn handle_query_v5(db: Database, responder: Arc<QueryResponder>, client: v5::AsyncClient, metrics: Arc<Metrics>, msg: PublishV5) -> Result<()> {
+    debug!("Processing MQTT v5 label query = {:?}", msg);
+    let query = match query_v5(&msg) {
+        Ok(query) => query,
+        Err(e) => {
+            error!("Error = {e}");
+            return Ok(());
+        }
+    };
+    let reply_topic = query.reply_topic.clone();
+    let properties = PublishProperties {
+        correlation_data: Some(query.correlation.clone().into()),
+        ..Default::default()
+    };
+    if let Some(reply) = answer_query(&db, &responder, &metrics, query).await? {
+        client.publish_with_properties(reply_topic, v5::mqttbytes::QoS::AtLeastOnce, false, reply, properties).await?;
+    }
+    Ok(())
+}
+
+/// Answers queries on a separate MQTT v5 connection, only ends on shutdown when `broker_v5` is not configured
+#[allow(clippy::too_many_arguments)]
+async fn query_v5_task(options: Option<v5::MqttOptions>, mut reconnect: Reconnect, db: Database, responder: Option<Arc<QueryResponder>>, metrics: Arc<Metrics>, connected: watch::Sender<bool>, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    let (Some(options), Some(responder)) = (options, responder) else {
+        connected.send_replace(true);
+        let _ = shutdown.wait_for(|stop| *stop).await;
+        return Ok(());
+    };
+    let (client, mut eventloop) = v5::AsyncClient::new(options, 10);
+    loop {
+        let event = select! {
+            event = eventloop.poll() => event,
+            _ = shutdown.changed() => {
+                if !*connected.borrow() {
+                    return Ok(());
+                }
+                client.try_disconnect()?;
+                continue;
+            }
+        };
+        match event {
+            Ok(v5::Event::Incoming(PacketV5::Publish(msg))) => {
+                task::spawn(handle_query_v5(db.clone(), responder.clone(), client.clone(), metrics.clone(), msg));
+            }
+            Ok(v5::Event::Incoming(PacketV5::ConnAck(_))) => {
+                reconnect.connected();
+                connected.send_replace(true);
+                client.subscribe(responder.topics.topic.clone(), v5::mqttbytes::QoS::AtLeastOnce).await?;
+            }
+            Ok(v5::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
+                info!("Disconnected the query connection");
+                return Ok(());
+            }
+            Ok(event) => {
+                debug!("Query connection received event = {:?}", event);
+            }
+            Err(e) => {
+                connected.send_replace(false);
+                if !reconnect.error() {
+                    break Err(eyre!("Query connection exceeded the error budget of {}", reconnect.budget()));
+                }
+                error!("Query connection failed: {e:?}");
+                reconnect.backoff().await;
+            }
+        }
+    }
+}
+
+fn mqtt_options(cfg: &Config) -> Result<MqttOptions> {
+    info!("broker = {}", cfg.broker);
+    let mut mqttoptions = conf::mqtt_options(&cfg.broker)?;
+    mqttoptions.set_keep_alive(Duration::from_secs(30));
+    cfg.session.apply(&mut mqttoptions);
+    if cfg.session.expiry_secs.is_some() {
+        warn!("The session expiry is only sent by MQTT v5 connections, the broker decides for MQTT 3");
+    }
+    conf::apply_security(&mut mqttoptions, cfg.tls.as_ref(), cfg.broker_auth.as_ref())?;
+    Ok(mqttoptions)
+}
+
+fn query_mqtt_options_v5(cfg: &Config, url: &str) -> Result<v5::MqttOptions> {
+    info!("query broker = {url}");
+    let mut mqttoptions = conf::mqtt_options_v5(url)?;
+    mqttoptions.set_keep_alive(Duration::from_secs(30));
+    conf::apply_security_v5(&mut mqttoptions, cfg.tls.as_ref(), cfg.broker_auth.as_ref())?;
+    Ok(mqttoptions)
+}
+
+/// Publishes a signed status without waiting, the event loop which would make room is not polled meanwhile
+fn publish_status(broker: &AsyncClient, status: &StatusAnnouncer, state: State) -> Result<()> {
+    debug!("Status {state:?}");
+    if let Err(e) = broker.try_publish(status.topic(), QoS::AtLeastOnce, true, status.message(state)?) {
+        warn!("Skipped the {state:?} status: {e}");
+    }
+    Ok(())
+}
+
+/// Time without an info after which the retained infos sent after the subscription are loaded
+const BOOTSTRAP_QUIET: Duration = Duration::from_secs(1);
+
+/// The burst of retained infos the broker sends right after acknowledging the subscription, the database is only
+/// loaded once the burst is over
+#[derive(Default)]
+struct Bootstrap {
+    quiet_until: Option<tokio::time::Instant>,
+}
+
+impl Bootstrap {
+    /// The subscription was acknowledged, the retained infos follow
+    fn subscribed(&mut self) {
+        self.quiet_until = Some(tokio::time::Instant::now() + BOOTSTRAP_QUIET);
+    }
+
+    /// An info arrived, during the burst it is extended
+    fn info(&mut self) {
+        if let Some(quiet_until) = &mut self.quiet_until {
+            *quiet_until = tokio::time::Instant::now() + BOOTSTRAP_QUIET;
+        }
+    }
+
+    /// Returns once the burst is over, never outside of one
+    async fn done(&mut self) {
+        match self.quiet_until {
+            Some(quiet_until) => {
+                tokio::time::sleep_until(quiet_until).await;
+                self.quiet_until = None;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn broker_task(broker_mqttoptions: MqttOptions, mut reconnect: Reconnect, mls_topic: String, mls_qos: QoS, verify_key: Arc<PublicKey>, db: Database, query: Option<Arc<QueryResponder>>, audit: Option<Arc<AuditLog>>, metrics: Arc<Metrics>, status: Option<StatusAnnouncer>, connected: watch::Sender<bool>, loaded: watch::Sender<bool>, mut shutdown: watch::Receiver<bool>) -> Result<()> {
     let (broker, mut broker_eventloop) = AsyncClient::new(broker_mqttoptions, 10);
+    let mls_topic: Arc<str> = mls_topic.into();
+    let heartbeat_secs = status.as_ref().map_or(0, |status| status.heartbeat_secs());
+    let period = Duration::from_secs(heartbeat_secs.max(1));
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut bootstrap = Bootstrap::default();
     loop {
-        match broker_eventloop.poll().await {
+        let event = select! {
+            event = broker_eventloop.poll() => event,
+            _ = bootstrap.done() => {
+                info!("Loaded the retained infos");
+                loaded.send_replace(true);
+                continue;
+            }
+            _ = heartbeat.tick(), if heartbeat_secs > 0 && *connected.borrow() => {
+                if let Some(status) = &status {
+                    publish_status(&broker, status, State::Heartbeat)?;
+                }
+                continue;
+            }
+            _ = shutdown.changed() => {
+                if !*connected.borrow() {
+                    return Ok(());
+                }
+                if let Some(status) = &status {
+                    publish_status(&broker, status, State::Offline)?;
+                }
+                broker.try_disconnect()?;
+                continue;
+            }
+        };
+        match event {
             Ok(notification) => {
                 match notification {
                     Incoming(Packet::Publish(msg)) => {
-                        task::spawn(handle_topic_info(db.clone(), verify_key.clone(), msg));
+                        match &query {
+                            Some(responder) if responder.topics.topic == msg.topic => {
+                                task::spawn(handle_query(db.clone(), responder.clone(), broker.clone(), metrics.clone(), msg));
+                            }
+                            _ => {
+                                bootstrap.info();
+                                task::spawn(handle_topic_info(db.clone(), verify_key.clone(), mls_topic.clone(), audit.clone(), metrics.clone(), msg));
+                            }
+                        }
                     }
                     Incoming(Packet::ConnAck(_)) => {
-                        error_broker.reset();
-                        broker.subscribe(topic.clone(), QoS::AtMostOnce).await?;
+                        reconnect.connected();
+                        connected.send_replace(true);
+                        if let Some(status) = &status {
+                            publish_status(&broker, status, State::Online)?;
+                            heartbeat.reset();
+                        }
+                        // The retained infos under mls_topic rebuild the database right after a start
+                        broker.subscribe_many([
+                            SubscribeFilter::new(mls_topic.to_string(), mls_qos),
+                            SubscribeFilter::new(format!("{mls_topic}/#"), mls_qos),
+                        ]).await?;
+                        if let Some(responder) = &query {
+                            broker.subscribe(responder.topics.topic.clone(), QoS::AtLeastOnce).await?;
+                        }
+                    }
+                    Incoming(Packet::SubAck(_)) if !*loaded.borrow() => {
+                        // The broker sends the retained infos right after acknowledging the subscription
+                        bootstrap.subscribed();
                     }
                     Incoming(incoming) => {
                         debug!("Received Incoming event = {:?}", incoming);
                     }
+                    Outgoing(rumqttc::Outgoing::Disconnect) => {
+                        info!("Disconnected from the broker");
+                        return Ok(());
+                    }
                     Outgoing(outgoing) => {
                         debug!("Received Outgoing event = {:?}", outgoing);
                     }
                 };
             }
             Err(err) => {
-                error_broker.inc();
+                connected.send_replace(false);
+                if !reconnect.error() {
+                    break Err(eyre!("Broker connection exceeded the error budget of {}", reconnect.budget()));
+                }
+                // The broker publishes the Last Will with the datetime it was signed at
+                if let Some(status) = &status {
+                    status.apply(&mut broker_eventloop.mqtt_options)?;
+                }
                 match err {
                     ConnectionError::MqttState(_) => {}
                     ConnectionError::FlushTimeout
                     | ConnectionError::Tls(_)
                     | ConnectionError::NotConnAck(_)
-                    | ConnectionError::RequestsDone => {
+                    | ConnectionError::RequestsDone
+                    | ConnectionError::WsConnect(_) => {
                         error!("{:?}", err);
+                        reconnect.backoff().await;
                     }
                     ConnectionError::Io(_)
                     | ConnectionError::NetworkTimeout
-                    | ConnectionError::ConnectionRefused(_) => {
-                        tokio::time::sleep(Duration::from_secs(3)).await;
+                    | ConnectionError::ConnectionRefused(_)
+                    | ConnectionError::Websocket(_) => {
+                        reconnect.backoff().await;
                     }
                 }
             }
         }
-        if error_broker.is_too_mutch() {
-            break Err(eyre!("Broker connection exeded the error limit"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retained_info_topic() {
+        let info = LabeledInfo::new("sensors/a", 2);
+        assert_eq!(LabeledInfo::retained_topic("mls/info", "sensors/a"), "mls/info/sensors/a");
+        assert!(info.matches_info_topic("mls/info", "mls/info/sensors/a"));
+        assert!(info.matches_info_topic("mls/info", "mls/info"));
+        assert!(!info.matches_info_topic("mls/info", "mls/info/sensors/b"));
+    }
+
+    #[test]
+    fn v5_query() {
+        let properties = PublishProperties {
+            response_topic: Some("mls/reply/1".into()),
+            correlation_data: Some(vec![1, 2].into()),
+            ..Default::default()
+        };
+        let msg = PublishV5::new("mls/query", v5::mqttbytes::QoS::AtLeastOnce, "a/b", Some(properties));
+        assert_eq!(query_v5(&msg).unwrap(), LabelQuery::new("a/b", "mls/reply/1", vec![1, 2]));
+        // Without a response topic the payload is a query like over MQTT v3
+        let fallback = LabelQuery::new("a/c", "mls/reply/2", vec![3]);
+        let msg = PublishV5::new("mls/query", v5::mqttbytes::QoS::AtLeastOnce, fallback.serialize().unwrap(), None);
+        assert_eq!(query_v5(&msg).unwrap(), fallback);
+    }
+
+    #[tokio::test]
+    async fn topic_infos() {
+        use ed25519_dalek::SigningKey;
+
+        let (db, _handle) = Database::new();
+        let secret = SigningKey::from_bytes(&[3; 32]);
+        let verify_key = Arc::new(PublicKey::new(secret.verifying_key()));
+        let info_key = Key::new(secret, "proxy.info".into());
+        let metrics = Arc::new(Metrics::default());
+        let info = |topic: &str, label| {
+            let mut payload = Vec::new();
+            ciborium::ser::into_writer(&info_key.sign(LabeledInfo::new(topic, label).serialize().unwrap()), &mut payload).unwrap();
+            Publish::new(LabeledInfo::retained_topic("mls/info", topic), QoS::AtLeastOnce, payload)
+        };
+        let handle = |msg| handle_topic_info(db.clone(), verify_key.clone(), "mls/info".into(), None, metrics.clone(), msg);
+        handle(info("a/b", 2)).await.unwrap();
+        assert_eq!(db.get("a/b".into()).await.unwrap(), DBResult::Some(2));
+        // The proxy cleared the retained info
+        handle(Publish::new("mls/info/a/b", QoS::AtLeastOnce, Vec::new())).await.unwrap();
+        assert_eq!(db.get("a/b".into()).await.unwrap(), DBResult::Some(2));
+    }
+
+    #[tokio::test]
+    async fn query_clearance() {
+        use ed25519_dalek::SigningKey;
+
+        let (db, _handle) = Database::new();
+        db.insert("a/low".into(), 1).await.unwrap();
+        db.insert("a/high".into(), 3).await.unwrap();
+        let secret = SigningKey::from_bytes(&[5; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let responder = QueryResponder {
+            topics: QueryTopics { topic: "mls/query".into(), reply_prefix: "mls/reply/".into() },
+            key: Key::new(secret, "label_db.query".into()),
+            clearances: Arc::new(Clearances {
+                users: BTreeMap::from([("fog".to_string(), 2)]),
+                superusers: vec!["proxy".into()],
+            }),
+        };
+        let metrics = Metrics::default();
+        let answer = |topic: &'static str, reply_topic: &'static str| {
+            let (db, responder, metrics, public) = (&db, &responder, &metrics, &public);
+            async move {
+                let reply = answer_query(db, responder, metrics, LabelQuery::new(topic, reply_topic, vec![1])).await.unwrap();
+                reply.map(|reply| LabelResponse::from_signed(&reply, public).unwrap().result)
+            }
+        };
+        assert_eq!(answer("a/low", "mls/reply/fog/1").await, Some(DBResult::Some(1)));
+        // The label of a topic above the clearance of the requester stays hidden
+        assert_eq!(answer("a/high", "mls/reply/fog/1").await, Some(DBResult::Denied(RequestError::InsufficientClearance)));
+        assert_eq!(answer("a/high", "mls/reply/proxy").await, Some(DBResult::Some(3)));
+        assert_eq!(answer("a/low", "mls/reply/nobody/1").await, None);
+        assert_eq!(answer("a/low", "a/low").await, None);
+    }
+
+    #[tokio::test]
+    async fn query_authorization() {
+        let (db, _handle) = Database::new();
+        let metrics = Metrics::default();
+        db.insert("a/low".into(), 1).await.unwrap();
+        let auth = AuthConfig { listen: "127.0.0.1:0".parse().unwrap(), allow_unlabeled: true, allow_vhosts: false };
+        let clearances = Clearances {
+            users: BTreeMap::from([("fog".to_string(), 2)]),
+            superusers: vec!["label_db".into()],
+        };
+        let query = QueryTopics { topic: "mls/query".into(), reply_prefix: "mls/reply/".into() };
+        let allowed = |user: &'static str, filter: &'static str, access| {
+            let (auth, clearances, db, metrics, query) = (&auth, &clearances, &db, &metrics, &query);
+            async move { is_authorized(auth, clearances, db, metrics, "mls/info", Some(query), user, filter, access).await.unwrap() }
+        };
+        assert!(allowed("fog", "mls/query", Access::Write).await);
+        assert!(allowed("fog", "mls/reply/fog/#", Access::Read).await);
+        assert!(allowed("fog", "mls/reply/fog/1", Access::Read).await);
+        // Even with unlabeled topics allowed nobody else reads or forges the replies
+        assert!(!allowed("fog", "mls/reply/other/1", Access::Read).await);
+        assert!(!allowed("fog", "mls/reply/+/1", Access::Read).await);
+        assert!(!allowed("fog", "mls/#", Access::Read).await);
+        assert!(!allowed("fog", "mls/reply/fog/1", Access::Write).await);
+        assert!(!allowed("nobody", "mls/query", Access::Write).await);
+        assert!(allowed("label_db", "mls/reply/fog/1", Access::Write).await);
+        assert!(allowed("fog", "a/#", Access::Read).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loaded_after_retained_infos() {
+        let mut bootstrap = Bootstrap::default();
+        assert!(tokio::time::timeout(Duration::from_secs(60), bootstrap.done()).await.is_err());
+        bootstrap.subscribed();
+        let start = tokio::time::Instant::now();
+        // Every info of the burst extends it
+        for _ in 0..3 {
+            tokio::time::advance(BOOTSTRAP_QUIET / 2).await;
+            bootstrap.info();
+        }
+        bootstrap.done().await;
+        assert_eq!(start.elapsed(), BOOTSTRAP_QUIET / 2 * 3 + BOOTSTRAP_QUIET);
+        // Infos after the burst do not start another one
+        bootstrap.info();
+        assert!(tokio::time::timeout(Duration::from_secs(60), bootstrap.done()).await.is_err());
+    }
+
+    #[test]
+    fn rabbitmq_routing_key() {
+        assert_eq!(routing_key_to_topic("sensors.*.temp"), "sensors/+/temp");
+        assert_eq!(routing_key_to_topic("a/b.#"), "a.b/#");
+    }
+
+    #[tokio::test]
+    async fn socket_query_metrics() {
+        let (db, _handle) = Database::new();
+        let metrics = Metrics::default();
+        db.insert("a/b".into(), 2).await.unwrap();
+        assert_eq!(handle_get("a/b", &db, &metrics).await.unwrap(), "2\n");
+        handle_get("a/c", &db, &metrics).await.unwrap();
+        handle_check("1 a/#", &db, &metrics).await.unwrap();
+        assert_eq!(handle_check("high a/#", &db, &metrics).await.unwrap(), "Denied InvalidRequest\n");
+        assert_eq!(handle_set("0 a/b", &db, false, None).await.unwrap(), "Denied Forbidden\n");
+        assert_eq!(handle_set("a/b", &db, true, None).await.unwrap(), "Denied InvalidRequest\n");
+        assert_eq!(handle_set("high a/b", &db, true, None).await.unwrap(), "Denied InvalidRequest\n");
+        assert_eq!(db.get("a/b".into()).await.unwrap(), DBResult::Some(2));
+        assert_eq!(metrics.queries.get(&["get", "Some"]), 1);
+        assert_eq!(metrics.queries.get(&["get", "None"]), 1);
+        assert_eq!(metrics.queries.get(&["check", "Denied"]), 1);
+        assert!(metrics.registry.render().contains("mls_label_db_lookup_seconds_count{request=\"get\"} 2\n"));
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_answered() {
+        let (db, _handle) = Database::new();
+        let (client, server) = UnixStream::pair().unwrap();
+        let handler = task::spawn(handle_request(server, db, true, None, Arc::new(Metrics::default())));
+        let (reader, mut writer) = client.into_split();
+        let mut replies = BufReader::new(reader).lines();
+        // The connection stays open after a bad request
+        for request in ["DELETE a/b", "SET a/b", "GET a/b"] {
+            writer.write_all(format!("{request}\n").as_bytes()).await.unwrap();
+        }
+        drop(writer);
+        let mut answers = Vec::new();
+        while let Some(line) = replies.next_line().await.unwrap() {
+            answers.push(line);
+        }
+        assert_eq!(answers, vec!["Denied InvalidRequest", "Denied InvalidRequest", "None"]);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_limit() {
+        let (db, _handle) = Database::new();
+        let (client, server) = UnixStream::pair().unwrap();
+        let handler = task::spawn(handle_request(server, db, false, None, Arc::new(Metrics::default())));
+        let (reader, mut writer) = client.into_split();
+        writer.write_all(b"GET ").await.unwrap();
+        // The endless line is cut off at the limit and the connection closed
+        let endless = task::spawn(async move {
+            let chunk = vec![b'a'; 4096];
+            while writer.write_all(&chunk).await.is_ok() {}
+        });
+        let mut reply = String::new();
+        BufReader::new(reader).read_line(&mut reply).await.unwrap();
+        assert_eq!(reply, "Denied InvalidRequest\n");
+        assert!(handler.await.unwrap().is_err());
+        endless.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_authorization() {
+        let (db, _handle) = Database::new();
+        let metrics = Metrics::default();
+        db.insert("a/low".into(), 1).await.unwrap();
+        db.insert("a/high".into(), 3).await.unwrap();
+        db.insert("a/mid".into(), 2).await.unwrap();
+        let auth = AuthConfig { listen: "127.0.0.1:0".parse().unwrap(), allow_unlabeled: false, allow_vhosts: false };
+        let clearances = Clearances {
+            users: BTreeMap::from([("fog".to_string(), 2)]),
+            superusers: vec!["proxy".into()],
+        };
+        let allowed = |user: &'static str, topic: &'static str| {
+            let (auth, clearances, db, metrics) = (&auth, &clearances, &db, &metrics);
+            async move { is_authorized(auth, clearances, db, metrics, "mls/info", None, user, topic, Access::Write).await.unwrap() }
+        };
+        assert!(allowed("fog", "a/mid").await);
+        // No write down
+        assert!(!allowed("fog", "a/low").await);
+        assert!(!allowed("fog", "a/high").await);
+        assert!(!allowed("fog", "a/unlabeled").await);
+        assert!(!allowed("fog", "mls/info/a/low").await);
+        assert!(!allowed("nobody", "a/mid").await);
+        assert!(allowed("proxy", "mls/info/a/low").await);
+        assert!(!allowed("fog", "mls/info").await);
+        // A readwrite request needs both
+        let read_write = |topic: &'static str| is_authorized(&auth, &clearances, &db, &metrics, "mls/info", None, "fog", topic, Access::ReadWrite);
+        assert!(read_write("a/mid").await.unwrap());
+        assert!(!read_write("a/low").await.unwrap());
+        assert!(!read_write("a/high").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn vhost_requests() {
+        let (db, _handle) = Database::new();
+        let clearances = Arc::new(Clearances {
+            users: BTreeMap::from([("fog".to_string(), 2)]),
+            superusers: vec!["proxy".into()],
+        });
+        let request = |user: &str| {
+            let mut req = Request { method: "POST".into(), path: "/vhost".into(), ..Default::default() };
+            req.params.insert("username".into(), user.into());
+            req
+        };
+        let mut auth = AuthConfig { listen: "127.0.0.1:0".parse().unwrap(), allow_unlabeled: false, allow_vhosts: false };
+        for allow_vhosts in [false, true] {
+            auth.allow_vhosts = allow_vhosts;
+            let answer = |user| handle_auth(request(user), Arc::new(auth.clone()), clearances.clone(), db.clone(), Arc::new(Metrics::default()), Arc::new("mls/info".into()), Arc::new(None));
+            assert_eq!(answer("proxy").await.status(), 200);
+            assert_eq!(answer("fog").await.status(), if allow_vhosts { 200 } else { 403 });
         }
     }
+
+    #[test]
+    fn acl_requests() {
+        let mut req = Request {
+            method: "POST".into(),
+            path: "/acl".into(),
+            ..Default::default()
+        };
+        req.params.insert("username".into(), "fog".into());
+        req.params.insert("topic".into(), "a/#".into());
+        req.params.insert("acc".into(), "4".into());
+        assert_eq!(parse_acl_request(&req), Some(("fog".into(), "a/#".into(), Access::Read)));
+        req.params.insert("acc".into(), "2".into());
+        assert_eq!(parse_acl_request(&req), Some(("fog".into(), "a/#".into(), Access::Write)));
+        req.params.insert("acc".into(), "3".into());
+        assert_eq!(parse_acl_request(&req), Some(("fog".into(), "a/#".into(), Access::ReadWrite)));
+    }
 }
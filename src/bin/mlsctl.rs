@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Builder;
+
+use mls::{
+    Label,
+    acl,
+    audit::{self, AuditError},
+    labeldb_client::{ClientError, LabelDbClient},
+    query::QueryTopics,
+    topicdb::{DBResult, RequestError},
+};
+
+/// Query and administer a running label_db
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// path to the label_db socket
+    #[arg(short, long, global = true, default_value = "/tmp/mls/labeldb.sock")]
+    socket: PathBuf,
+    /// print JSON instead of human-readable output
+    #[arg(short, long, global = true)]
+    json: bool,
+    /// seconds to wait for label_db to answer
+    #[arg(short, long, global = true, default_value_t = 5)]
+    timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Get the label of a topic or topic filter
+    Get {
+        topic: String,
+    },
+    /// List the labeled topics matched by a topic filter
+    List {
+        #[arg(default_value = "#")]
+        filter: String,
+    },
+    /// Check if a clearance may subscribe to a topic filter, exits with 1 if not
+    Check {
+        clearance: Label,
+        filter: String,
+    },
+    /// Write every labeled topic to a file or stdout
+    Dump {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load labeled topics from a file written by dump, `-` reads stdin, label_db needs `allow_set`
+    Import {
+        input: PathBuf,
+    },
+    /// Show database statistics
+    Stats,
+    /// Render broker ACLs from the labeled topics
+    Acl {
+        format: AclFormat,
+        /// clearance of a user as `<user>=<label>`
+        #[arg(short, long = "clearance", value_parser = parse_clearance)]
+        clearances: Vec<(String, Label)>,
+        /// user which may access every topic
+        #[arg(long = "superuser")]
+        superusers: Vec<String>,
+        /// prefix of the info topics only superusers may publish
+        #[arg(long, default_value = "mls/info")]
+        mls_topic: String,
+        /// query topic of label_db every user may publish to
+        #[arg(long)]
+        query_topic: Option<String>,
+        /// prefix of the reply topics, each user may read the replies below `<prefix><user>`
+        #[arg(long, default_value = "mls/reply/")]
+        reply_prefix: String,
+        /// RabbitMQ virtual host
+        #[arg(long, default_value = "/")]
+        vhost: String,
+        /// RabbitMQ topic exchange
+        #[arg(long, default_value = "amq.topic")]
+        exchange: String,
+    },
+    /// Verify the hash chain of an audit log of the proxy or label_db, exits with 1 if it was modified
+    Audit {
+        log: PathBuf,
+        /// hash of a record noted earlier, detects records removed from the end, defaults to the hash in `<log>.head`
+        #[arg(long)]
+        head: Option<String>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AclFormat {
+    /// mosquitto acl_file
+    Mosquitto,
+    /// shell script with rabbitmqctl set_topic_permissions calls
+    Rabbitmq,
+}
+
+fn parse_clearance(arg: &str) -> Result<(String, Label)> {
+    let (user, label) = arg
+        .split_once('=')
+        .ok_or_else(|| eyre!("Expected `<user>=<label>` but got `{arg}`"))?;
+    Ok((user.to_string(), label.parse()?))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TopicLabel {
+    topic: String,
+    label: Label,
+}
+
+fn print_result(result: DBResult, json: bool) -> Result<()> {
+    if json {
+        let value = match result {
+            DBResult::None => serde_json::json!({"result": "none"}),
+            DBResult::Some(label) => serde_json::json!({"result": "some", "label": label}),
+            DBResult::Denied(reason) => serde_json::json!({"result": "denied", "reason": reason.to_string()}),
+        };
+        println!("{value}");
+    } else {
+        println!("{result}");
+    }
+    Ok(())
+}
+
+fn format_topics(topics: Vec<(String, Label)>, json: bool) -> Result<String> {
+    if json {
+        let topics: Vec<TopicLabel> = topics
+            .into_iter()
+            .map(|(topic, label)| TopicLabel { topic, label })
+            .collect();
+        Ok(serde_json::to_string_pretty(&topics)? + "\n")
+    } else {
+        Ok(topics
+            .into_iter()
+            .map(|(topic, label)| format!("{label} {topic}\n"))
+            .collect())
+    }
+}
+
+fn parse_topics(input: &str, json: bool) -> Result<Vec<(String, Label)>> {
+    if json {
+        let topics: Vec<TopicLabel> = serde_json::from_str(input)?;
+        return Ok(topics.into_iter().map(|t| (t.topic, t.label)).collect());
+    }
+    input
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (label, topic) = line
+                .split_once(' ')
+                .ok_or_else(|| eyre!("Expected `<label> <topic>` but got `{line}`"))?;
+            Ok((topic.to_string(), label.parse()?))
+        })
+        .collect()
+}
+
+async fn run(args: Args) -> Result<ExitCode> {
+    let client = LabelDbClient::new(&args.socket)
+        .with_pool_size(1)
+        .with_timeout(Duration::from_secs(args.timeout));
+    match args.command {
+        Command::Get { topic } => {
+            print_result(client.get(&topic).await?, args.json)?;
+        }
+        Command::Check { clearance, filter } => {
+            let result = client.check(&filter, clearance).await?;
+            print_result(result, args.json)?;
+            if let DBResult::Denied(_) = result {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::List { filter } => {
+            print!("{}", format_topics(client.list(&filter).await?, args.json)?);
+        }
+        Command::Dump { output } => {
+            let dump = format_topics(client.list("#").await?, args.json)?;
+            match output {
+                Some(path) => fs::write(path, dump)?,
+                None => print!("{dump}"),
+            }
+        }
+        Command::Import { input } => {
+            let mut content = String::new();
+            if input.as_os_str() == "-" {
+                io::stdin().read_to_string(&mut content)?;
+            } else {
+                content = fs::read_to_string(input)?;
+            }
+            let topics = parse_topics(&content, args.json)?;
+            let count = topics.len();
+            for (topic, label) in topics {
+                match client.insert(&topic, label).await {
+                    Err(ClientError::Denied(RequestError::Forbidden)) => {
+                        return Err(eyre!("label_db does not allow SET, enable allow_set in its config to import"));
+                    }
+                    result => result?,
+                }
+            }
+            if !args.json {
+                println!("Imported {count} topics");
+            }
+        }
+        Command::Stats => {
+            let stats = client.stats().await?;
+            if args.json {
+                let stats: serde_json::Map<String, serde_json::Value> = stats
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect();
+                println!("{}", serde_json::Value::Object(stats));
+            } else {
+                for (name, value) in stats {
+                    println!("{name}: {value}");
+                }
+            }
+        }
+        Command::Acl { format, clearances, superusers, mls_topic, query_topic, reply_prefix, vhost, exchange } => {
+            let topics = client.list("#").await?;
+            let clearances: BTreeMap<String, Label> = clearances.into_iter().collect();
+            let query = query_topic.map(|topic| QueryTopics { topic, reply_prefix });
+            match format {
+                AclFormat::Mosquitto => print!("{}", acl::mosquitto(&topics, &clearances, &superusers, &mls_topic, query.as_ref())),
+                AclFormat::Rabbitmq => print!("{}", acl::rabbitmq(&topics, &clearances, &superusers, &mls_topic, query.as_ref(), &vhost, &exchange)),
+            }
+        }
+        Command::Audit { log, head } => {
+            let head = match head {
+                Some(head) => Ok(Some(head)),
+                None => audit::read_head(&audit::head_path(&log)),
+            };
+            let verified = head.and_then(|head| audit::verify(BufReader::new(fs::File::open(&log)?), head.as_deref()));
+            match verified {
+                Ok(verified) if args.json => {
+                    println!("{}", serde_json::json!({"result": "ok", "records": verified.records, "head": verified.head}));
+                }
+                Ok(verified) => println!("OK {} records, head {}", verified.records, verified.head),
+                Err(AuditError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    if args.json {
+                        println!("{}", serde_json::json!({"result": "invalid", "reason": e.to_string()}));
+                    } else {
+                        println!("INVALID {e}");
+                    }
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_socket() {
+        // mlsctl inside the label_db image reaches label_db without -s
+        let cfg: toml::Value = toml::from_str(include_str!("../../container/config/labeldb.conf.toml")).unwrap();
+        let args = Args::parse_from(["mlsctl", "dump"]);
+        assert_eq!(cfg["socket_path"].as_str().map(PathBuf::from), Some(args.socket));
+    }
+
+    #[test]
+    fn dump_roundtrip() {
+        let topics = vec![("a/b".to_string(), 3), ("a/with space".to_string(), 0)];
+        for json in [false, true] {
+            let dump = format_topics(topics.clone(), json).unwrap();
+            assert_eq!(parse_topics(&dump, json).unwrap(), topics);
+        }
+    }
+}
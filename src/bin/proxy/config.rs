@@ -0,0 +1,430 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use mls::{
+    Label,
+    conf::{default_shutdown_timeout_secs, AuditConfig, ConfKey, HealthConfig, MetricsConfig, ConfPubKey, CredentialsConfig, ReconnectConfig, SessionConfig, StatusConfig, TlsConfig},
+};
+
+/// What happens to messages on topics which have no label in `topics`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum UnlabeledPolicy {
+    /// Do not forward the message
+    #[default]
+    Drop,
+    /// Forward the message with this label
+    Default { label: Label },
+    /// Forward the message with this label to `<prefix>/<topic>`
+    Quarantine { prefix: String, label: Label },
+}
+
+/// How the label and signature of a forwarded message are sent to a sink
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelTransport {
+    /// Wrap the payload in a CBOR `SignedMsg`
+    #[default]
+    Envelope,
+    /// Connect with MQTT v5, forward the payload unchanged and send the label in user properties
+    UserProperties,
+}
+
+/// Connection to a single MQTT broker
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BrokerConfig {
+    pub url: String,
+    /// Only used for sinks
+    #[serde(default)]
+    pub label_transport: LabelTransport,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub auth: Option<CredentialsConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    Json,
+    Cbor,
+}
+
+/// Labels messages by a field of their payload instead of their topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentRule {
+    /// Topic filter of the messages the rule inspects
+    pub topics: String,
+    pub format: PayloadFormat,
+    /// JSON pointer to the field, for CBOR it addresses map keys and array indices
+    pub path: String,
+    /// Label for each value of the field, other values fall back to the topic label.
+    /// A label below the topic label is raised to it, the topic is announced with the highest label
+    pub labels: HashMap<String, Label>,
+}
+
+/// The label of a topic filter, optionally with the QoS it is subscribed with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TopicRule {
+    Label(Label),
+    Rule { label: Label, qos: u8 },
+}
+
+impl TopicRule {
+    pub fn label(&self) -> Label {
+        match self {
+            TopicRule::Label(label) | TopicRule::Rule { label, .. } => *label,
+        }
+    }
+
+    pub fn qos(&self) -> Option<u8> {
+        match self {
+            TopicRule::Label(_) => None,
+            TopicRule::Rule { qos, .. } => Some(*qos),
+        }
+    }
+}
+
+/// Which topics of a source are labeled with which key and forwarded to which sink
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RouteConfig {
+    pub source: String,
+    pub sink: String,
+    pub label_key: ConfKey,
+    /// Subscription QoS of the topics without their own `qos` and of the content rules
+    #[serde(default)]
+    pub qos: u8,
+    pub topics: HashMap<String, TopicRule>,
+    /// Checked in order before the topic label
+    #[serde(default)]
+    pub content: Vec<ContentRule>,
+    /// Overrides the global `unlabeled` policy for this route
+    #[serde(default)]
+    pub unlabeled: Option<UnlabeledPolicy>,
+    /// Topic filters subscribed with `qos` only for the unlabeled policy, e.g. `#` to catch every topic without a rule.
+    /// Loading fails if they overlap the topics of a downlink to the source, the proxy would forward its own downlink messages
+    #[serde(default)]
+    pub unlabeled_topics: Vec<String>,
+}
+
+/// Forwarding of signed messages from a sink back to a source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DownlinkConfig {
+    pub sink: String,
+    pub source: String,
+    /// Topic filters subscribed on the sink
+    pub topics: Vec<String>,
+    /// Subscription QoS of the topics on the sink, a message is acknowledged to the sink once the source accepted it
+    #[serde(default)]
+    pub qos: u8,
+    /// Highest label which may be written down to the source
+    pub clearance: Label,
+    /// Forward only the payload instead of the whole `SignedMsg`, for edge devices without MLS support
+    #[serde(default)]
+    pub strip_envelope: bool,
+    /// Keys accepted for signatures of downlink messages
+    pub keys: Vec<ConfPubKey>,
+    /// Seconds a downlink message may be older or newer than the clock of the proxy
+    #[serde(default = "default_downlink_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_downlink_max_age_secs() -> u64 {
+    60
+}
+
+/// The single `source` and `sink` of older configs, see `Config::migrate`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LegacyConfig {
+    pub source: String,
+    pub sink: String,
+    pub topics: HashMap<String, Label>,
+    pub label_key: ConfKey,
+    #[serde(default)]
+    pub downlink: Option<LegacyDownlinkConfig>,
+}
+
+/// The downlink of older configs, from their sink to their source
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LegacyDownlinkConfig {
+    pub topics: Vec<String>,
+    pub clearance: Label,
+    #[serde(default)]
+    pub strip_envelope: bool,
+    pub keys: Vec<ConfPubKey>,
+}
+
+/// What happens to a message for a sink whose queue is full, label infos are only held up by `Block`.
+///
+/// Dropped QoS 1 and 2 messages are acknowledged to the source and counted in `mls_proxy_queue_dropped_total`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest messages except the label infos
+    #[default]
+    DropOldest,
+    DropNewest,
+    /// Stop reading from the source until the sink catches up
+    Block,
+}
+
+/// The queue between labeling and publishing of every sink
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Queued messages are kept in `<dir>/<sink>` and survive a restart, without it they are only in memory
+    pub dir: Option<PathBuf>,
+    pub max_messages: usize,
+    /// Limit of the topic, payload and property bytes in the queue
+    pub max_bytes: u64,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            dir: None,
+            max_messages: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub log_level: String,
+    /// The label of every forwarded topic is published retained to `<mls_topic>/<topic>`
+    pub mls_topic: String,
+    pub info_key: ConfKey,
+    #[serde(default)]
+    pub sources: BTreeMap<String, BrokerConfig>,
+    #[serde(default)]
+    pub sinks: BTreeMap<String, BrokerConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Keys of an older config, turned into `sources`, `sinks` and `routes` by `migrate`
+    #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+    pub legacy: Option<LegacyConfig>,
+    #[serde(default)]
+    pub unlabeled: UnlabeledPolicy,
+    #[serde(default)]
+    pub downlinks: Vec<DownlinkConfig>,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// Seconds between re-announcements of all known topic labels on `mls_topic`, 0 disables them
+    #[serde(default = "default_info_refresh_secs")]
+    pub info_refresh_secs: u64,
+    /// Seconds between checks if the config file changed, 0 only reloads on SIGHUP
+    #[serde(default = "default_config_poll_secs")]
+    pub config_poll_secs: u64,
+    /// Backoff and error budget of every source and sink connection
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    /// Liveness of the proxy on every sink
+    #[serde(default)]
+    pub status: Option<StatusConfig>,
+    /// Seconds to flush the sink queues after SIGINT or SIGTERM, a persisted queue of a sink which is not connected is kept for the next start
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Hash chained log of the labeling decisions of every forwarded or dropped message
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+    /// Prometheus endpoint
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    /// Liveness and readiness endpoint, ready once every source and sink is connected
+    #[serde(default)]
+    pub health: Option<HealthConfig>,
+}
+
+impl Config {
+    /// Turns the `source`, `sink`, `topics`, `label_key` and `downlink` of an older config into the source `edge`,
+    /// the sink `fog`, a route and a downlink between them, and rejects a config without sources or sinks
+    pub fn migrate(mut self) -> Result<Self> {
+        if let Some(legacy) = self.legacy.take() {
+            if !self.sources.is_empty() || !self.sinks.is_empty() || !self.routes.is_empty() {
+                return Err(eyre!("The config has the old source and sink keys besides sources, sinks or routes, remove the old keys"));
+            }
+            let broker = |url| BrokerConfig {
+                url,
+                label_transport: LabelTransport::Envelope,
+                session: SessionConfig::default(),
+                tls: None,
+                auth: None,
+            };
+            self.sources.insert("edge".into(), broker(legacy.source));
+            self.sinks.insert("fog".into(), broker(legacy.sink));
+            self.routes.push(RouteConfig {
+                source: "edge".into(),
+                sink: "fog".into(),
+                label_key: legacy.label_key,
+                qos: 0,
+                topics: legacy.topics.into_iter().map(|(topic, label)| (topic, TopicRule::Label(label))).collect(),
+                content: Vec::new(),
+                unlabeled: None,
+                unlabeled_topics: Vec::new(),
+            });
+            self.downlinks.extend(legacy.downlink.map(|downlink| DownlinkConfig {
+                sink: "fog".into(),
+                source: "edge".into(),
+                topics: downlink.topics,
+                qos: 0,
+                clearance: downlink.clearance,
+                strip_envelope: downlink.strip_envelope,
+                keys: downlink.keys,
+                max_age_secs: default_downlink_max_age_secs(),
+            }));
+        }
+        if self.sources.is_empty() || self.sinks.is_empty() {
+            return Err(eyre!("The config needs [sources.<name>], [sinks.<name>] and [[routes]], \
+                or all of source, sink, topics and label_key of the older single broker config"));
+        }
+        Ok(self)
+    }
+
+    /// Rejects brokers connected twice with the same `client_id`, the connections would take over each others session
+    pub fn check_client_ids(&self) -> Result<()> {
+        let mut ids = HashMap::new();
+        for (name, broker) in self.sources.iter().chain(&self.sinks) {
+            let options = mls::conf::mqtt_options(&broker.url)?;
+            if let Some(other) = ids.insert((options.broker_address(), options.client_id()), name) {
+                return Err(eyre!("The brokers {other} and {name} use the same client_id {}", options.client_id()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_config_poll_secs() -> u64 {
+    5
+}
+
+fn default_info_refresh_secs() -> u64 {
+    300
+}
+
+impl ::std::default::Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".into(),
+            mls_topic: "mls/info".into(),
+            info_key: ConfKey{
+                id: "proxy.info.1".into(),
+                path: "./data/info.key".into(),
+            },
+            sources: BTreeMap::from([("edge".into(), BrokerConfig {
+                url: "mqtt://localhost:1883?client_id=1".into(),
+                label_transport: LabelTransport::Envelope,
+                session: SessionConfig::default(),
+                tls: None,
+                auth: None,
+            })]),
+            sinks: BTreeMap::from([("fog".into(), BrokerConfig {
+                url: "mqtt://localhost:1883?client_id=2".into(),
+                label_transport: LabelTransport::Envelope,
+                session: SessionConfig::default(),
+                tls: None,
+                auth: None,
+            })]),
+            routes: vec![RouteConfig {
+                source: "edge".into(),
+                sink: "fog".into(),
+                label_key: ConfKey{
+                    id: "proxy.label.1".into(),
+                    path: "./data/label.key".into(),
+                },
+                qos: 0,
+                topics: HashMap::new(),
+                content: Vec::new(),
+                unlabeled: None,
+                unlabeled_topics: Vec::new(),
+            }],
+            legacy: None,
+            unlabeled: UnlabeledPolicy::Drop,
+            downlinks: Vec::new(),
+            queue: QueueConfig::default(),
+            info_refresh_secs: default_info_refresh_secs(),
+            config_poll_secs: default_config_poll_secs(),
+            reconnect: ReconnectConfig::default(),
+            status: None,
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+            audit: None,
+            metrics: None,
+            health: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_config() {
+        let cfg: Config = toml::from_str(include_str!("../../../container/config/proxy.conf.toml")).unwrap();
+        assert_eq!(cfg.sources["edge"].url, "mqtt://edge_broker:1883?client_id=mls_proxy.1");
+        assert_eq!(cfg.routes.len(), 1);
+        assert_eq!(cfg.routes[0].sink, "fog");
+        assert_eq!(cfg.routes[0].topics["sensors/+/temp"], TopicRule::Rule { label: 3, qos: 2 });
+        assert_eq!(cfg.routes[0].topics["hello"], TopicRule::Label(4));
+        assert_eq!(cfg.routes[0].qos, 1);
+        assert!(cfg.routes[0].unlabeled.is_none());
+        assert_eq!(cfg.routes[0].unlabeled_topics, vec!["devices/#".to_string()]);
+        assert_eq!(cfg.sinks["fog"].label_transport, LabelTransport::Envelope);
+        assert_eq!(cfg.sources["edge"].session.persistent, Some(true));
+        assert_eq!(cfg.sinks["fog"].session.expiry_secs, Some(3600));
+        cfg.check_client_ids().unwrap();
+        assert_eq!(cfg.reconnect, ReconnectConfig::default());
+        assert_eq!(cfg.health.unwrap().listen, "127.0.0.1:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn legacy_config() {
+        let legacy = r#"
+            source = 'mqtt://edge_broker:1883?client_id=mls_proxy.1'
+            sink = 'mqtt://fog_broker:1883?client_id=mls_proxy.1'
+            log_level = "debug"
+            label_key = { path = '/usr/local/etc/mls/data/label.key', id = 'proxy.label.1' }
+            info_key = { path = '/usr/local/etc/mls/data/info.key', id = 'proxy.info.1' }
+            mls_topic = "mls/info"
+
+            [topics]
+            "sensors/#" = 2
+
+            [downlink]
+            topics = ["actuators/#"]
+            clearance = 2
+            keys = []
+        "#;
+        let cfg: Config = toml::from_str(legacy).unwrap();
+        let cfg = cfg.migrate().unwrap();
+        assert_eq!(cfg.sources["edge"].url, "mqtt://edge_broker:1883?client_id=mls_proxy.1");
+        assert_eq!(cfg.sinks["fog"].url, "mqtt://fog_broker:1883?client_id=mls_proxy.1");
+        assert_eq!(cfg.routes[0].topics["sensors/#"], TopicRule::Label(2));
+        assert_eq!(cfg.routes[0].label_key.id, "proxy.label.1");
+        assert_eq!((cfg.downlinks[0].sink.as_str(), cfg.downlinks[0].clearance), ("fog", 2));
+
+        // Without topics and label_key the old keys are not a config
+        let incomplete = legacy.replace("[topics]", "[other]");
+        let cfg: Config = toml::from_str(&incomplete).unwrap();
+        assert!(cfg.migrate().unwrap_err().to_string().contains("older single broker config"));
+        assert!(Config::default().migrate().is_ok());
+    }
+
+    #[test]
+    fn duplicate_client_ids() {
+        let mut cfg = Config::default();
+        cfg.check_client_ids().unwrap();
+        cfg.sinks.get_mut("fog").unwrap().url = "mqtt://localhost:1883?client_id=1".into();
+        assert!(cfg.check_client_ids().is_err());
+        cfg.sinks.get_mut("fog").unwrap().url = "mqtt://other:1883?client_id=1".into();
+        cfg.check_client_ids().unwrap();
+    }
+}
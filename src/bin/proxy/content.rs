@@ -0,0 +1,118 @@
+use ciborium::value::Value;
+use eyre::{eyre, Result};
+
+use mls::{Label, topicdb};
+
+use crate::config::{ContentRule, PayloadFormat};
+
+impl ContentRule {
+    pub fn validate(&self) -> Result<()> {
+        if !topicdb::is_valid_filter(&self.topics) {
+            return Err(eyre!("The content rule topic filter {} is not valid", self.topics));
+        }
+        if !self.path.is_empty() && !self.path.starts_with('/') {
+            return Err(eyre!("The content rule path {} is not a JSON pointer", self.path));
+        }
+        Ok(())
+    }
+
+    /// Returns the label for the value at `path` in the payload of a message on `topic`.
+    ///
+    /// Payloads which can not be decoded and values without a label do not match.
+    pub fn label(&self, topic: &str, payload: &[u8]) -> Option<Label> {
+        if !topicdb::matches(&self.topics, topic) {
+            return None;
+        }
+        let value = match self.format {
+            PayloadFormat::Json => json_value(payload, &self.path),
+            PayloadFormat::Cbor => cbor_value(payload, &self.path),
+        }?;
+        self.labels.get(&value).copied()
+    }
+}
+
+fn json_value(payload: &[u8], path: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    match json.pointer(path)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Follows a JSON pointer through CBOR maps with text or integer keys and arrays
+fn cbor_value(payload: &[u8], path: &str) -> Option<String> {
+    let mut value: Value = ciborium::de::from_reader(payload).ok()?;
+    for token in path.split('/').skip(1) {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        value = match value {
+            Value::Map(entries) => entries
+                .into_iter()
+                .find(|(key, _)| match key {
+                    Value::Text(key) => *key == token,
+                    Value::Integer(key) => i128::from(*key).to_string() == token,
+                    _ => false,
+                })
+                .map(|(_, value)| value)?,
+            Value::Array(mut items) => {
+                let index: usize = token.parse().ok()?;
+                if index >= items.len() {
+                    return None;
+                }
+                items.swap_remove(index)
+            }
+            _ => return None,
+        };
+    }
+    match value {
+        Value::Text(s) => Some(s),
+        Value::Integer(i) => Some(i128::from(i).to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn rule(format: PayloadFormat, path: &str) -> ContentRule {
+        ContentRule {
+            topics: "sensors/#".into(),
+            format,
+            path: path.into(),
+            labels: HashMap::from([("secret".to_string(), 4), ("public".to_string(), 0), ("17".to_string(), 2)]),
+        }
+    }
+
+    #[test]
+    fn json_rules() {
+        let classification = rule(PayloadFormat::Json, "/classification");
+        assert_eq!(classification.label("sensors/a", br#"{"classification":"secret","v":1}"#), Some(4));
+        assert_eq!(classification.label("sensors/a", br#"{"classification":"other"}"#), None);
+        assert_eq!(classification.label("sensors/a", b"not json"), None);
+        assert_eq!(classification.label("other", br#"{"classification":"secret"}"#), None);
+        let device = rule(PayloadFormat::Json, "/meta/devices/0");
+        assert_eq!(device.label("sensors/a", br#"{"meta":{"devices":[17, 3]}}"#), Some(2));
+        assert!(rule(PayloadFormat::Json, "classification").validate().is_err());
+    }
+
+    #[test]
+    fn cbor_rules() {
+        let encode = |value: Value| {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&value, &mut bytes).unwrap();
+            bytes
+        };
+        let payload = encode(Value::Map(vec![
+            (Value::Text("a/b".into()), Value::Text("public".into())),
+            (Value::Integer(7.into()), Value::Array(vec![Value::Integer(1.into()), Value::Integer(17.into())])),
+        ]));
+        assert_eq!(rule(PayloadFormat::Cbor, "/a~1b").label("sensors/x", &payload), Some(0));
+        assert_eq!(rule(PayloadFormat::Cbor, "/7/1").label("sensors/x", &payload), Some(2));
+        assert_eq!(rule(PayloadFormat::Cbor, "/7/2").label("sensors/x", &payload), None);
+        assert_eq!(rule(PayloadFormat::Cbor, "").label("sensors/x", &encode(Value::Text("secret".into()))), Some(4));
+    }
+}
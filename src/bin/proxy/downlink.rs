@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use eyre::{eyre, Result};
+use log::warn;
+use rumqttc::{AsyncClient, QoS};
+
+use mls::{
+    Label,
+    PublicKey,
+    SignedMsg,
+    dominates,
+    metrics::Counter,
+    properties::LabelProperties,
+    topicdb,
+};
+
+use crate::config::{Config, DownlinkConfig};
+use crate::metrics::Metrics;
+use crate::sink::{Inflight, SourceAck};
+
+/// The newest signature time accepted from a key on a topic and the signatures accepted within that second
+#[derive(Default)]
+struct HighWater {
+    datetime: i64,
+    signatures: HashSet<Vec<u8>>,
+}
+
+impl HighWater {
+    /// Returns false for a message older than the newest one or a message seen before
+    fn advance(&mut self, datetime: i64, signature: &[u8]) -> bool {
+        if datetime < self.datetime {
+            return false;
+        }
+        if datetime > self.datetime {
+            self.datetime = datetime;
+            self.signatures.clear();
+        }
+        self.signatures.insert(signature.to_vec())
+    }
+}
+
+pub struct Downlink {
+    pub sink: String,
+    pub source: String,
+    topics: Vec<String>,
+    qos: QoS,
+    clearance: Label,
+    strip_envelope: bool,
+    max_age_secs: i64,
+    keys: HashMap<String, PublicKey>,
+    /// By key id and topic, so commands of one key to several topics do not replay each other
+    high_water: HashMap<(String, String), HighWater>,
+    signature_failures: Arc<Counter>,
+    dropped: Arc<Counter>,
+}
+
+impl Downlink {
+    pub fn load(cfg: &DownlinkConfig, running: &Config, metrics: &Metrics) -> Result<Self> {
+        if !running.sinks.contains_key(&cfg.sink) {
+            return Err(eyre!("The downlink uses the unknown sink {}", cfg.sink));
+        }
+        if !running.sources.contains_key(&cfg.source) {
+            return Err(eyre!("The downlink uses the unknown source {}", cfg.source));
+        }
+        if let Some(filter) = cfg.topics.iter().find(|filter| !topicdb::is_valid_filter(filter)) {
+            return Err(eyre!("The downlink topic filter {filter} is not valid"));
+        }
+        let qos = rumqttc::qos(cfg.qos).map_err(|_| eyre!("The QoS {} of the downlink is not valid", cfg.qos))?;
+        let mut keys = HashMap::new();
+        for key in &cfg.keys {
+            // Accepting our own signatures would send uplink messages straight back to the source
+            if running.routes.iter().any(|route| route.label_key.id == key.id) {
+                return Err(eyre!("The downlink must not accept the label key {} of the proxy", key.id));
+            }
+            keys.insert(key.id.clone(), key.get_key()?);
+        }
+        Ok(Downlink {
+            sink: cfg.sink.clone(),
+            source: cfg.source.clone(),
+            topics: cfg.topics.clone(),
+            qos,
+            clearance: cfg.clearance,
+            strip_envelope: cfg.strip_envelope,
+            max_age_secs: cfg.max_age_secs as i64,
+            keys,
+            high_water: HashMap::new(),
+            signature_failures: metrics.signature_failures.clone(),
+            dropped: metrics.dropped_downlinks.clone(),
+        })
+    }
+
+    /// Verifies a message from the sink and returns the payload to write down to the source.
+    ///
+    /// Messages with an unknown key, a bad signature, a label above the clearance, a signature for
+    /// another topic, or which are too old or replayed are rejected.
+    pub fn check(&mut self, topic: &str, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let signed_msg: SignedMsg = ciborium::de::from_reader(payload)?;
+        self.check_signed(topic, &signed_msg, chrono::Utc::now().timestamp())
+    }
+
+    /// Like `check` for a message from an MQTT v5 sink with the label in user properties.
+    ///
+    /// Without `strip_envelope` the message is written down as the equivalent `SignedMsg`.
+    pub fn check_properties(&mut self, topic: &str, payload: &[u8], properties: &[(String, String)]) -> Result<Option<Vec<u8>>> {
+        let signed_msg = LabelProperties::from_user_properties(properties)?.into_signed_msg(payload.to_vec())?;
+        self.check_signed(topic, &signed_msg, chrono::Utc::now().timestamp())
+    }
+
+    fn check_signed(&mut self, topic: &str, signed_msg: &SignedMsg, now: i64) -> Result<Option<Vec<u8>>> {
+        let Some(key) = self.keys.get(signed_msg.get_key_id()) else {
+            warn!("Rejected downlink message on {topic}: unknown key {}", signed_msg.get_key_id());
+            self.signature_failures.inc(&[signed_msg.get_key_id()]);
+            return Ok(None);
+        };
+        let (inner_payload, ad) = signed_msg
+            .verify_additional(key)
+            .inspect_err(|_| self.signature_failures.inc(&[signed_msg.get_key_id()]))?;
+        if ad.topic.as_deref() != Some(topic) {
+            warn!("Rejected downlink message on {topic}: signed for the topic {:?}", ad.topic);
+            return Ok(None);
+        }
+        if !dominates(self.clearance, ad.label) {
+            warn!("Rejected downlink message on {topic}: label {} is above {}", ad.label, self.clearance);
+            return Ok(None);
+        }
+        let datetime = signed_msg.get_datetime();
+        if (now - datetime).abs() > self.max_age_secs {
+            warn!("Rejected downlink message on {topic}: signed at {datetime}, more than {}s from now", self.max_age_secs);
+            return Ok(None);
+        }
+        let high_water = self.high_water.entry((signed_msg.get_key_id().to_string(), topic.to_string())).or_default();
+        if !high_water.advance(datetime, signed_msg.get_signature()) {
+            warn!("Rejected downlink message on {topic}: replayed or older than the last message of {} on it", signed_msg.get_key_id());
+            return Ok(None);
+        }
+        if self.strip_envelope {
+            Ok(Some(inner_payload.to_vec()))
+        } else {
+            let mut buffer = Vec::new();
+            ciborium::ser::into_writer(signed_msg, &mut buffer)?;
+            Ok(Some(buffer))
+        }
+    }
+
+    /// Publishes a verified message to the source without waiting, so a busy source can not stall the sink.
+    ///
+    /// `ack` is kept until the source acknowledged the message. The message is dropped, counted and acknowledged to
+    /// the sink if the request queue of the source is full, like a message dropped by a full sink queue.
+    pub fn write_down(&self, source: &AsyncClient, inflight: &Inflight, topic: &str, qos: QoS, payload: Vec<u8>, ack: Option<Arc<SourceAck>>) {
+        if let Err(e) = inflight.try_publish(source, topic, qos, payload, ack) {
+            warn!("Dropped downlink message on {topic} to source {}: {e}", self.source);
+            self.dropped.inc(&[&self.source]);
+        }
+    }
+
+    /// Returns true if a message on `topic` received from the sink belongs to this downlink
+    pub fn matches(&self, topic: &str) -> bool {
+        self.topics.iter().any(|filter| topicdb::matches(filter, topic))
+    }
+
+    pub fn filters(&self) -> Vec<rumqttc::SubscribeFilter> {
+        self.topics
+            .iter()
+            .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), self.qos))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use mls::{AdditionalData, Key};
+
+    fn downlink(secret: &SigningKey, metrics: &Metrics) -> Downlink {
+        Downlink {
+            sink: "fog".into(),
+            source: "edge".into(),
+            topics: vec!["actuators/#".into()],
+            qos: QoS::AtLeastOnce,
+            clearance: 2,
+            strip_envelope: true,
+            max_age_secs: 60,
+            keys: HashMap::from([("fog.label.1".to_string(), PublicKey::new(secret.verifying_key()))]),
+            high_water: HashMap::new(),
+            signature_failures: metrics.signature_failures.clone(),
+            dropped: metrics.dropped_downlinks.clone(),
+        }
+    }
+
+    #[test]
+    fn downlink_no_write_down() {
+        let secret = SigningKey::from_bytes(&[3; 32]);
+        let metrics = Metrics::default();
+        let mut downlink = downlink(&secret, &metrics);
+        let sign = |key_id: &str, label: Label| {
+            let key = Key::new(secret.clone(), key_id.into());
+            let msg = key.sign_with_ad(b"open".to_vec(), AdditionalData::new(label).with_topic("actuators/door").serialize().unwrap());
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&msg, &mut bytes).unwrap();
+            bytes
+        };
+        assert!(downlink.matches("actuators/door"));
+        assert!(!downlink.matches("sensors/door"));
+        let door = sign("fog.label.1", 2);
+        assert_eq!(downlink.check("actuators/door", &door).unwrap(), Some(b"open".to_vec()));
+        // The same message again is a replay
+        assert_eq!(downlink.check("actuators/door", &door).unwrap(), None);
+        // A signature for another topic does not open the gate
+        assert_eq!(downlink.check("actuators/gate", &sign("fog.label.1", 2)).unwrap(), None);
+        assert_eq!(downlink.check("actuators/door", &sign("fog.label.1", 3)).unwrap(), None);
+        assert_eq!(downlink.check("actuators/door", &sign("other", 1)).unwrap(), None);
+        assert!(downlink.check("actuators/door", b"not cbor").is_err());
+        let stale: SignedMsg = ciborium::de::from_reader(&sign("fog.label.1", 1)[..]).unwrap();
+        assert_eq!(downlink.check_signed("actuators/door", &stale, stale.get_datetime() + 61).unwrap(), None);
+
+        let properties = Key::new(secret.clone(), "fog.label.1".into()).sign_properties(b"open", 1, "actuators/door").unwrap();
+        let user_properties = properties.to_user_properties();
+        assert_eq!(downlink.check_properties("actuators/door", b"open", &user_properties).unwrap(), Some(b"open".to_vec()));
+        assert!(downlink.check_properties("actuators/door", b"close", &user_properties).is_err());
+        assert_eq!(metrics.signature_failures.get(&["other"]), 1);
+        assert_eq!(metrics.signature_failures.get(&["fog.label.1"]), 1);
+    }
+
+    #[tokio::test]
+    async fn sink_ack_after_source_ack() {
+        use rumqttc::Request;
+
+        let metrics = Metrics::default();
+        let downlink = downlink(&SigningKey::from_bytes(&[3; 32]), &metrics);
+        assert!(downlink.filters().iter().all(|filter| filter.qos == QoS::AtLeastOnce));
+        let (sink_tx, sink_rx) = flume::unbounded();
+        let sink = AsyncClient::from_senders(sink_tx);
+        let (source_tx, source_rx) = flume::unbounded();
+        let source = AsyncClient::from_senders(source_tx);
+        let inflight = Inflight::default();
+        let mut publish = rumqttc::Publish::new("actuators/door", QoS::AtLeastOnce, b"open".to_vec());
+        publish.pkid = 5;
+        downlink.write_down(&source, &inflight, "actuators/door", QoS::AtLeastOnce, b"open".to_vec(), SourceAck::new(&sink, &publish));
+        assert!(matches!(source_rx.recv_async().await.unwrap(), Request::Publish(_)));
+        // The sink gets the ack only once the source acknowledged the message
+        inflight.published(9);
+        tokio::task::yield_now().await;
+        assert!(sink_rx.is_empty());
+        inflight.acknowledged(9);
+        let ack = tokio::time::timeout(std::time::Duration::from_secs(5), sink_rx.recv_async()).await.unwrap().unwrap();
+        assert!(matches!(&ack, Request::PubAck(ack) if ack.pkid == 5), "{ack:?}");
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn replays_per_topic() {
+        use ed25519_dalek::Signer;
+
+        let secret = SigningKey::from_bytes(&[3; 32]);
+        let metrics = Metrics::default();
+        let mut downlink = downlink(&secret, &metrics);
+        // The transcript of `Key::sign_with_ad` at a given time
+        let sign_at = |topic: &str, datetime: i64| {
+            let ad = AdditionalData::new(1).with_topic(topic).serialize().unwrap();
+            let transcript = [&b"open"[..], &ad, &datetime.to_be_bytes(), b"fog.label.1"].concat();
+            let properties = LabelProperties {
+                label: 1,
+                key_id: "fog.label.1".into(),
+                datetime,
+                signature: secret.sign(&transcript).to_vec(),
+                topic: topic.into(),
+            };
+            properties.into_signed_msg(b"open".to_vec()).unwrap()
+        };
+        let now = chrono::Utc::now().timestamp();
+        assert_eq!(downlink.check_signed("actuators/gate", &sign_at("actuators/gate", now), now).unwrap(), Some(b"open".to_vec()));
+        // An older command of the same key to another topic, e.g. redelivered out of order, is not a replay
+        let door = sign_at("actuators/door", now - 5);
+        assert_eq!(downlink.check_signed("actuators/door", &door, now).unwrap(), Some(b"open".to_vec()));
+        assert_eq!(downlink.check_signed("actuators/door", &door, now).unwrap(), None);
+        assert_eq!(downlink.check_signed("actuators/gate", &sign_at("actuators/gate", now - 1), now).unwrap(), None);
+    }
+
+    #[test]
+    fn high_water_mark() {
+        let mut high_water = HighWater::default();
+        assert!(high_water.advance(10, b"a"));
+        assert!(high_water.advance(10, b"b"));
+        assert!(!high_water.advance(10, b"a"));
+        assert!(high_water.advance(11, b"a"));
+        assert!(!high_water.advance(10, b"c"));
+    }
+}
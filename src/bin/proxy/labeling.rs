@@ -0,0 +1,507 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use eyre::{eyre, Result};
+use log::{debug, warn};
+use rumqttc::QoS;
+
+use mls::{
+    lru::Lru,
+    metrics::Counter,
+    AdditionalData,
+    Label,
+    LabeledInfo,
+    Key,
+    topicdb::{self, TopicDB},
+};
+
+use crate::config::{Config, ContentRule, UnlabeledPolicy};
+
+impl UnlabeledPolicy {
+    /// Returns the topic and label to forward a message on an unlabeled topic with
+    pub fn apply(&self, topic: &str) -> Option<(String, Label)> {
+        match self {
+            UnlabeledPolicy::Drop => None,
+            UnlabeledPolicy::Default { label } => Some((topic.to_string(), *label)),
+            UnlabeledPolicy::Quarantine { prefix, label } => Some((format!("{prefix}/{topic}"), *label)),
+        }
+    }
+}
+
+/// A route with its rules and label key loaded
+pub struct Route {
+    pub source: String,
+    pub sink: String,
+    /// Topic filters with their subscription QoS, including the filters of content rules and of the unlabeled policy
+    pub subscriptions: BTreeMap<String, QoS>,
+    pub rules: TopicDB,
+    pub content: Vec<ContentRule>,
+    pub unlabeled: UnlabeledPolicy,
+    pub label_key: Key,
+}
+
+impl Route {
+    /// The label of the first matching content rule, otherwise the label of the topic.
+    ///
+    /// A content rule only raises the label, a payload can not declassify its topic.
+    pub fn label(&self, topic: &str, payload: &[u8]) -> Option<Label> {
+        let topic_label = self.rules.lookup(topic);
+        match self.content.iter().find_map(|rule| rule.label(topic, payload)) {
+            Some(label) => Some(topic_label.map_or(label, |topic_label| topic_label.max(label))),
+            None => topic_label,
+        }
+    }
+
+    /// The highest label a message on `topic` can get from the topic and content rules
+    pub fn max_label(&self, topic: &str) -> Option<Label> {
+        self.content
+            .iter()
+            .filter(|rule| topicdb::matches(&rule.topics, topic))
+            .flat_map(|rule| rule.labels.values().copied())
+            .chain(self.rules.lookup(topic))
+            .max()
+    }
+}
+
+/// The part of the configuration which is replaced when the config file is reloaded
+pub struct Labeling {
+    pub routes: Vec<Route>,
+    pub info_key: Key,
+}
+
+impl Labeling {
+    /// Validates the config and reads the keys
+    pub fn load(cfg: &Config) -> Result<Self> {
+        let mut routes = Vec::with_capacity(cfg.routes.len());
+        for route in &cfg.routes {
+            if !cfg.sources.contains_key(&route.source) {
+                return Err(eyre!("The route uses the unknown source {}", route.source));
+            }
+            if !cfg.sinks.contains_key(&route.sink) {
+                return Err(eyre!("The route uses the unknown sink {}", route.sink));
+            }
+            if route.label_key.id == cfg.info_key.id {
+                return Err(eyre!("The ids of the label key of the route {} -> {} and the info key are the same", route.source, route.sink));
+            }
+            if let Some(filter) = route.topics.keys().chain(&route.unlabeled_topics).find(|filter| !topicdb::is_valid_filter(filter)) {
+                return Err(eyre!("The topic filter {filter} is not valid"));
+            }
+            let route_qos = rumqttc::qos(route.qos).map_err(|_| eyre!("The QoS {} is not valid", route.qos))?;
+            let mut subscriptions = BTreeMap::new();
+            for rule in &route.content {
+                rule.validate()?;
+                subscriptions.insert(rule.topics.clone(), route_qos);
+            }
+            let mut rules = TopicDB::new();
+            for (filter, rule) in &route.topics {
+                rules.insert(filter, rule.label());
+                let qos = match rule.qos() {
+                    Some(qos) => rumqttc::qos(qos).map_err(|_| eyre!("The QoS {qos} of {filter} is not valid"))?,
+                    None => route_qos,
+                };
+                subscribe(&mut subscriptions, filter, qos);
+            }
+            for filter in &route.unlabeled_topics {
+                subscribe(&mut subscriptions, filter, route_qos);
+            }
+            // The route would pick up the messages the proxy writes down to its source and forward them again
+            for downlink in cfg.downlinks.iter().filter(|downlink| downlink.source == route.source) {
+                let overlap = subscriptions
+                    .keys()
+                    .flat_map(|filter| downlink.topics.iter().map(move |topic| (filter, topic)))
+                    .find(|(filter, topic)| topicdb::overlaps(filter, topic));
+                if let Some((filter, topic)) = overlap {
+                    return Err(eyre!("The topic filter {filter} of the route {} -> {} overlaps the downlink topic {topic} to the source", route.source, route.sink));
+                }
+            }
+            routes.push(Route {
+                source: route.source.clone(),
+                sink: route.sink.clone(),
+                subscriptions,
+                rules,
+                content: route.content.clone(),
+                unlabeled: route.unlabeled.clone().unwrap_or_else(|| cfg.unlabeled.clone()),
+                label_key: route.label_key.get_key()?,
+            });
+        }
+        Ok(Labeling {
+            routes,
+            info_key: cfg.info_key.get_key()?,
+        })
+    }
+
+    pub fn routes_from<'a>(&'a self, source: &'a str) -> impl Iterator<Item = &'a Route> {
+        self.routes.iter().filter(move |route| route.source == source)
+    }
+
+    /// The topic filters of all routes from `source` with the highest QoS any route wants
+    pub fn topics(&self, source: &str) -> BTreeMap<String, QoS> {
+        let mut topics = BTreeMap::new();
+        for route in self.routes_from(source) {
+            for (filter, qos) in &route.subscriptions {
+                subscribe(&mut topics, filter, *qos);
+            }
+        }
+        topics
+    }
+
+    pub fn filters(&self, source: &str) -> Vec<rumqttc::SubscribeFilter> {
+        self.topics(source)
+            .into_iter()
+            .map(|(topic, qos)| rumqttc::SubscribeFilter::new(topic, qos))
+            .collect()
+    }
+
+    /// True if a route to `sink` still labels `topic`, or may forward it by its unlabeled policy
+    pub fn announces(&self, sink: &str, topic: &str) -> bool {
+        self.routes
+            .iter()
+            .filter(|route| route.sink == sink)
+            .any(|route| route.max_label(topic).is_some() || route.unlabeled != UnlabeledPolicy::Drop)
+    }
+
+    /// Returns the routes a message from `source` is forwarded on, with the topic and label to use
+    /// and the label to announce for the topic.
+    ///
+    /// A message is forwarded on every route with a content or topic rule for it. Only when no route
+    /// of the source has a rule, the unlabeled policy of each route applies. The announced label is
+    /// the highest label of any message on the topic, so a topic with content rules does not flip
+    /// between labels in label_db and its readers need the clearance for every message on it.
+    pub fn forward<'a>(&'a self, source: &str, topic: &str, payload: &[u8], unlabeled: &mut UnlabeledTopics) -> Vec<(&'a Route, String, Label, Label)> {
+        let routes = || self.routes.iter().filter(|route| route.source == source);
+        let labeled: Vec<_> = routes()
+            .filter_map(|route| {
+                let label = route.label(topic, payload)?;
+                let announced = route.max_label(topic).map_or(label, |max| max.max(label));
+                Some((route, topic.to_string(), label, announced))
+            })
+            .collect();
+        if !labeled.is_empty() {
+            return labeled;
+        }
+        unlabeled.record(topic);
+        routes()
+            .filter_map(|route| route.unlabeled.apply(topic).map(|(topic, label)| (route, topic, label, label)))
+            .collect()
+    }
+}
+
+/// Adds a subscription, a filter subscribed twice keeps the higher QoS
+fn subscribe(subscriptions: &mut BTreeMap<String, QoS>, filter: &str, qos: QoS) {
+    let entry = subscriptions.entry(filter.to_string()).or_insert(qos);
+    if qos > *entry {
+        *entry = qos;
+    }
+}
+
+/// Returns the filters only in `new` or with another QoS, and the filters only in `old`
+pub fn diff_filters(old: &BTreeMap<String, QoS>, new: &BTreeMap<String, QoS>) -> (Vec<(String, QoS)>, Vec<String>) {
+    let added = new
+        .iter()
+        .filter(|(filter, qos)| old.get(*filter) != Some(*qos))
+        .map(|(filter, qos)| (filter.clone(), *qos))
+        .collect();
+    let removed = old.keys().filter(|filter| !new.contains_key(*filter)).cloned().collect();
+    (added, removed)
+}
+
+/// Unlabeled topics whose messages are counted, a topic seen again after it was forgotten is warned about again
+const MAX_UNLABELED_TOPICS: usize = 10_000;
+/// Announced topics which are refreshed, a forgotten topic is announced again with its next message
+const MAX_ANNOUNCEMENTS: usize = 100_000;
+
+/// Counts the messages received on each topic without a label
+pub struct UnlabeledTopics {
+    seen: Lru<String, u64>,
+    total: u64,
+    /// Counter of the messages by topic and the source label, a forgotten topic is no longer exported
+    metric: Option<(Arc<Counter>, String)>,
+}
+
+impl Default for UnlabeledTopics {
+    fn default() -> Self {
+        UnlabeledTopics {
+            seen: Lru::new(MAX_UNLABELED_TOPICS),
+            total: 0,
+            metric: None,
+        }
+    }
+}
+
+impl UnlabeledTopics {
+    /// Counts the messages of each remembered topic in `messages`, labeled with `source` and the topic
+    pub fn with_metric(mut self, messages: Arc<Counter>, source: &str) -> Self {
+        self.metric = Some((messages, source.into()));
+        self
+    }
+
+    fn record(&mut self, topic: &str) {
+        self.total += 1;
+        if let Some((messages, source)) = &self.metric {
+            if self.seen.is_full() && self.seen.get(&topic.to_string()).is_none() {
+                if let Some(oldest) = self.seen.oldest() {
+                    messages.remove(&[source, oldest]);
+                }
+            }
+            messages.inc(&[source, topic]);
+        }
+        let count = self.seen.get_or_insert_with(topic.to_string(), || 0);
+        *count += 1;
+        if *count == 1 {
+            warn!("Received message on unlabeled topic {topic}");
+        } else {
+            debug!("Received message {count} on unlabeled topic {topic}");
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+/// Remembers the label announced on the mls topic for the recently forwarded topics of each sink
+#[derive(Debug)]
+pub struct Announcements {
+    announced: Lru<(String, String), Label>,
+}
+
+impl Default for Announcements {
+    fn default() -> Self {
+        Announcements {
+            announced: Lru::new(MAX_ANNOUNCEMENTS),
+        }
+    }
+}
+
+impl Announcements {
+    /// Returns true if the label of `topic` was not announced on `sink` yet or changed
+    pub fn update(&mut self, sink: &str, topic: &str, label: Label) -> bool {
+        let previous = self.announced.insert((sink.to_string(), topic.to_string()), label);
+        previous != Some(label)
+    }
+
+    pub fn remove(&mut self, sink: &str, topic: &str) -> Option<Label> {
+        self.announced.remove(&(sink.to_string(), topic.to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, Label)> {
+        self.announced.iter().map(|((sink, topic), label)| (sink.as_str(), topic.as_str(), *label))
+    }
+}
+
+/// Wraps the payload in a `SignedMsg` with the label and topic in the additional data
+pub fn label_msg(payload: &[u8], label: Label, topic: &str, label_key: &Key) -> Result<Vec<u8>> {
+    let ad = AdditionalData::new(label).with_topic(topic);
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() * 8);
+    let label_msg = label_key.sign_with_ad(payload.to_vec(), ad.serialize()?);
+    ciborium::ser::into_writer(&label_msg, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Signs the `LabeledInfo` published to the mls topic
+pub fn info_msg(topic: &str, label: Label, info_key: &Key) -> Result<Vec<u8>> {
+    let mut info_buf: Vec<u8> = Vec::with_capacity(4098);
+    let label_info = LabeledInfo::new(topic, label);
+    let info_msg = info_key.sign(label_info.serialize()?.to_vec());
+    ciborium::ser::into_writer(&info_msg, &mut info_buf)?;
+    Ok(info_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use ed25519_dalek::SigningKey;
+
+    fn route(source: &str, sink: &str, topics: &[(&str, Label)], unlabeled: UnlabeledPolicy) -> Route {
+        let mut rules = TopicDB::new();
+        for (filter, label) in topics {
+            rules.insert(filter, *label);
+        }
+        Route {
+            source: source.into(),
+            sink: sink.into(),
+            subscriptions: topics.iter().map(|(t, _)| (t.to_string(), QoS::AtMostOnce)).collect(),
+            rules,
+            content: Vec::new(),
+            unlabeled,
+            label_key: Key::new(SigningKey::from_bytes(&[1; 32]), format!("{sink}.label")),
+        }
+    }
+
+    #[test]
+    fn unlabeled_policy() {
+        assert_eq!(UnlabeledPolicy::Drop.apply("a/b"), None);
+        assert_eq!(UnlabeledPolicy::Default { label: 3 }.apply("a/b"), Some(("a/b".into(), 3)));
+        let quarantine = UnlabeledPolicy::Quarantine { prefix: "mls/quarantine".into(), label: 4 };
+        assert_eq!(quarantine.apply("c"), Some(("mls/quarantine/c".into(), 4)));
+
+        let cfg: UnlabeledPolicy = toml::from_str("policy = 'default'\nlabel = 2").unwrap();
+        assert_eq!(cfg, UnlabeledPolicy::Default { label: 2 });
+    }
+
+    #[test]
+    fn forward_routes() {
+        let labeling = Labeling {
+            routes: vec![
+                route("edge1", "fog", &[("sensors/#", 2)], UnlabeledPolicy::Drop),
+                route("edge1", "cloud", &[("sensors/+/temp", 1)], UnlabeledPolicy::Default { label: 4 }),
+                route("edge2", "fog", &[("sensors/#", 3)], UnlabeledPolicy::Drop),
+            ],
+            info_key: Key::new(SigningKey::from_bytes(&[2; 32]), "info".into()),
+        };
+        let mut unlabeled = UnlabeledTopics::default();
+        let forward = |source: &str, topic: &str, unlabeled: &mut UnlabeledTopics| -> Vec<(String, String, Label)> {
+            labeling
+                .forward(source, topic, b"{}", unlabeled)
+                .into_iter()
+                .map(|(route, topic, label, announced)| {
+                    assert_eq!(label, announced);
+                    (route.sink.clone(), topic, label)
+                })
+                .collect()
+        };
+        assert_eq!(forward("edge1", "sensors/a/temp", &mut unlabeled), vec![
+            ("fog".into(), "sensors/a/temp".into(), 2),
+            ("cloud".into(), "sensors/a/temp".into(), 1),
+        ]);
+        assert_eq!(forward("edge1", "sensors/a/hum", &mut unlabeled), vec![("fog".into(), "sensors/a/hum".into(), 2)]);
+        assert_eq!(forward("edge2", "sensors/a/temp", &mut unlabeled), vec![("fog".into(), "sensors/a/temp".into(), 3)]);
+        assert_eq!(unlabeled.total(), 0);
+        assert_eq!(forward("edge1", "other", &mut unlabeled), vec![("cloud".into(), "other".into(), 4)]);
+        assert_eq!(forward("edge2", "other", &mut unlabeled), vec![]);
+        assert_eq!(unlabeled.total(), 2);
+        assert_eq!(labeling.topics("edge1").into_keys().collect::<Vec<_>>(), vec!["sensors/#".to_string(), "sensors/+/temp".to_string()]);
+    }
+
+    #[test]
+    fn unlabeled_subscriptions() {
+        let mut cfg = Config::default();
+        cfg.routes[0].topics.insert("sensors/#".into(), crate::config::TopicRule::Label(2));
+        cfg.routes[0].unlabeled_topics.push("#".into());
+        cfg.routes[0].unlabeled = Some(UnlabeledPolicy::Quarantine { prefix: "mls/quarantine".into(), label: 4 });
+        let dir = std::env::temp_dir().join(format!("mls_unlabeled_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (key, seed) in [("label.key", 1), ("info.key", 2)] {
+            let keypair = ssh_key::private::Ed25519Keypair::from(SigningKey::from_bytes(&[seed; 32]));
+            let secret = ssh_key::PrivateKey::from(keypair);
+            secret.write_openssh_file(&dir.join(key), ssh_key::LineEnding::LF).unwrap();
+        }
+        cfg.routes[0].label_key.path = dir.join("label.key");
+        cfg.info_key.path = dir.join("info.key");
+        let labeling = Labeling::load(&cfg).unwrap();
+        assert_eq!(labeling.topics("edge").into_keys().collect::<Vec<_>>(), vec!["#".to_string(), "sensors/#".to_string()]);
+        cfg.routes[0].unlabeled_topics.push("a/#/b".into());
+        assert!(Labeling::load(&cfg).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // The topics caught only for the policy are counted per topic
+        let messages = mls::metrics::Registry::default().counter("unlabeled", "", &["source", "topic"]);
+        let mut unlabeled = UnlabeledTopics::default().with_metric(messages.clone(), "edge");
+        assert_eq!(labeling.forward("edge", "sensors/a", b"", &mut unlabeled)[0].1, "sensors/a");
+        let forwarded = labeling.forward("edge", "other", b"", &mut unlabeled);
+        assert_eq!((forwarded[0].1.as_str(), forwarded[0].2), ("mls/quarantine/other", 4));
+        labeling.forward("edge", "other", b"", &mut unlabeled);
+        assert_eq!(messages.get(&["edge", "other"]), 2);
+        assert_eq!(messages.get(&["edge", "sensors/a"]), 0);
+    }
+
+    #[test]
+    fn downlink_overlap() {
+        let sample = include_str!("../../../container/config/proxy.conf.toml");
+        let (routes, downlinks) = sample.split_at(sample.find("#[[downlinks]]").unwrap());
+        let uncommented: String = downlinks.lines().map(|line| format!("{}\n", line.trim_start_matches('#'))).collect();
+        let cfg: Config = toml::from_str(&format!("{routes}{uncommented}")).unwrap();
+        // The sample loads up to its keys, which only exist in the container
+        assert!(!Labeling::load(&cfg).err().unwrap().to_string().contains("overlaps"));
+        for unlabeled in ["#", "actuators/door", "+/+"] {
+            let mut cfg: Config = toml::from_str(&format!("{routes}{uncommented}")).unwrap();
+            cfg.routes[0].unlabeled_topics = vec![unlabeled.into()];
+            let err = Labeling::load(&cfg).err().unwrap().to_string();
+            assert!(err.contains(&format!("{unlabeled} of the route edge -> fog overlaps the downlink topic actuators/#")), "{err}");
+        }
+    }
+
+    #[test]
+    fn unlabeled_metric_bounded() {
+        let messages = mls::metrics::Registry::default().counter("unlabeled", "", &["source", "topic"]);
+        let mut unlabeled = UnlabeledTopics::default().with_metric(messages.clone(), "edge");
+        for topic in 0..=MAX_UNLABELED_TOPICS {
+            unlabeled.record(&topic.to_string());
+        }
+        // The first topic was forgotten
+        assert_eq!(messages.get(&["edge", "0"]), 0);
+        assert_eq!(messages.get(&["edge", "1"]), 1);
+        assert_eq!(unlabeled.total(), MAX_UNLABELED_TOPICS as u64 + 1);
+    }
+
+    #[test]
+    fn content_before_topic() {
+        use crate::config::{ContentRule, PayloadFormat};
+        let mut route = route("edge", "fog", &[("sensors/#", 2)], UnlabeledPolicy::Drop);
+        route.content.push(ContentRule {
+            topics: "mixed/#".into(),
+            format: PayloadFormat::Json,
+            path: "/classification".into(),
+            labels: HashMap::from([("secret".to_string(), 4)]),
+        });
+        route.content.push(ContentRule {
+            topics: "sensors/#".into(),
+            format: PayloadFormat::Json,
+            path: "/device".into(),
+            labels: HashMap::from([("cam1".to_string(), 3)]),
+        });
+        route.content.push(ContentRule {
+            topics: "sensors/#".into(),
+            format: PayloadFormat::Json,
+            path: "/classification".into(),
+            labels: HashMap::from([("public".to_string(), 0)]),
+        });
+        assert_eq!(route.label("sensors/a", br#"{"device":"cam1"}"#), Some(3));
+        assert_eq!(route.label("sensors/a", br#"{"device":"cam2"}"#), Some(2));
+        // A content rule can not declassify the topic
+        assert_eq!(route.label("sensors/a", br#"{"classification":"public"}"#), Some(2));
+        assert_eq!(route.label("mixed/a", br#"{"classification":"secret"}"#), Some(4));
+        assert_eq!(route.label("mixed/a", br#"{"classification":"public"}"#), None);
+        assert_eq!(route.max_label("sensors/a"), Some(3));
+        assert_eq!(route.max_label("mixed/a"), Some(4));
+        assert_eq!(route.max_label("other"), None);
+
+        let labeling = Labeling {
+            routes: vec![route],
+            info_key: Key::new(SigningKey::from_bytes(&[2; 32]), "info".into()),
+        };
+        let forwarded = labeling.forward("edge", "sensors/a", br#"{"device":"cam2"}"#, &mut UnlabeledTopics::default());
+        assert_eq!((forwarded[0].2, forwarded[0].3), (2, 3));
+    }
+
+    #[test]
+    fn announce_on_change() {
+        let mut announcements = Announcements::default();
+        assert!(announcements.update("fog", "a", 1));
+        assert!(!announcements.update("fog", "a", 1));
+        assert!(announcements.update("cloud", "a", 1));
+        assert!(announcements.update("fog", "a", 2));
+        assert!(!announcements.update("fog", "a", 2));
+        assert_eq!(announcements.iter().count(), 2);
+    }
+
+    #[test]
+    fn reload_diff() {
+        let mut old = BTreeMap::new();
+        subscribe(&mut old, "a/#", QoS::AtLeastOnce);
+        subscribe(&mut old, "a/#", QoS::AtMostOnce);
+        subscribe(&mut old, "b", QoS::AtMostOnce);
+        subscribe(&mut old, "d", QoS::AtMostOnce);
+        assert_eq!(old["a/#"], QoS::AtLeastOnce);
+        let new = BTreeMap::from([
+            ("a/#".to_string(), QoS::AtLeastOnce),
+            ("c/+".to_string(), QoS::AtMostOnce),
+            ("d".to_string(), QoS::ExactlyOnce),
+        ]);
+        assert_eq!(diff_filters(&old, &new), (
+            vec![("c/+".to_string(), QoS::AtMostOnce), ("d".to_string(), QoS::ExactlyOnce)],
+            vec!["b".to_string()],
+        ));
+    }
+}
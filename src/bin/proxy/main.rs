@@ -0,0 +1,604 @@
+use clap::Parser;
+use eyre::{eyre, Result};
+use log::{debug, error, info, warn};
+use rumqttc::{ AsyncClient, ConnectionError, Event::{Incoming, Outgoing}, Packet::{ConnAck, PubAck, PubComp, Publish}, QoS,};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::SystemTime;
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use tokio::runtime::Builder;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+
+use mls::{audit::{record_logged, AuditLog, Decision}, health::Health, reconnect::Reconnect, status::{State, StatusAnnouncer}, Label, LabeledInfo};
+
+mod config;
+mod content;
+mod downlink;
+mod labeling;
+mod metrics;
+mod queue;
+mod reload;
+mod sink;
+
+use config::{BrokerConfig, Config, LabelTransport};
+use downlink::Downlink;
+use labeling::{info_msg, Announcements, Labeling, UnlabeledTopics};
+use metrics::Metrics;
+use queue::Queue;
+use sink::{DownlinkSource, Inflight, QueuedSink, Sink, SourceAck};
+
+/// Simple program to greet a person
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// path to config file
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// ask the health endpoint of the running proxy if it is ready, exits with 1 if not
+    #[arg(long)]
+    healthcheck: bool,
+}
+
+fn setup_logger(level: &str) -> Result<()> {
+    fern::Dispatch::new()
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "[{} {} {}] {}",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                record.target(),
+                message
+            ))
+        })
+        .level(log::LevelFilter::from_str(level)?)
+        .chain(std::io::stdout())
+        .apply()?;
+    Ok(())
+}
+
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    dbg!(&args);
+    let home_conf = dbg!(confy::get_configuration_file_path("mls", "proxy.conf"));
+    let mut conf_path = Path::new("/usr/local/etc/mls/proxy.conf.toml");
+    
+    if let Some(ref path) = args.config {
+        // If the --config flag has been give use that path
+        conf_path = path
+    } else if let Ok(ref path) = home_conf{
+        if path.exists() {
+            conf_path = path
+        }
+    }
+    dbg!(&conf_path);
+    let cfg = confy::load_path::<Config>(conf_path)?.migrate()?;
+    if args.healthcheck {
+        let health = cfg.health.ok_or_else(|| eyre!("The health check needs the health endpoint in the config"))?;
+        let ready = mls::health::check(health.listen, Duration::from_secs(5))?;
+        return Ok(if ready { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+    let labeling = Labeling::load(&cfg)?;
+    setup_logger(&cfg.log_level)?;
+    
+    let conf_path = conf_path.to_path_buf();
+    Builder::new_multi_thread()
+        .enable_all()
+        .worker_threads(4)
+        .build()?
+        .block_on(async move { main_loop(cfg, conf_path, labeling).await })?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Runs the loop of a source until it disconnects, a loop which failed is restarted so the other sources and sinks keep running
+#[allow(clippy::too_many_arguments)]
+async fn source_task(
+    name: String,
+    mut eventloop: rumqttc::EventLoop,
+    source: AsyncClient,
+    inflight: Arc<Inflight>,
+    sinks: HashMap<String, QueuedSink>,
+    labeling: watch::Receiver<Arc<Labeling>>,
+    mls_topic: String,
+    announcements: Arc<Mutex<Announcements>>,
+    mut reconnect: Reconnect,
+    audit: Option<Arc<AuditLog>>,
+    metrics: Arc<Metrics>,
+    connected: watch::Sender<bool>,
+    mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    // Kept across restarts, so the exported counts of forgotten topics are removed
+    let mut unlabeled = UnlabeledTopics::default().with_metric(metrics.unlabeled.clone(), &name);
+    loop {
+        let result = source_loop(name.clone(), &mut eventloop, source.clone(), &inflight, sinks.clone(), labeling.clone(), mls_topic.clone(), announcements.clone(), &mut reconnect, &mut unlabeled, audit.clone(), metrics.clone(), connected.clone(), shutdown.clone()).await;
+        if let Err(e) = result {
+            if restart_site(&format!("Source {name}"), &name, e, &mut reconnect, &connected, &metrics, &mut shutdown).await {
+                continue;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Waits before the loop of a source or sink which failed runs again, returns false if the proxy shuts down meanwhile
+pub(crate) async fn restart_site(
+    site: &str,
+    name: &str,
+    err: eyre::Report,
+    reconnect: &mut Reconnect,
+    connected: &watch::Sender<bool>,
+    metrics: &Metrics,
+    shutdown: &mut watch::Receiver<bool>) -> bool {
+    connected.send_replace(false);
+    metrics.restarts.inc(&[name]);
+    let delay = reconnect.restart();
+    error!("{site} failed, restarting it in {}s: {err:#}", delay.as_secs());
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = shutdown.wait_for(|shutdown| *shutdown) => false,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn source_loop(
+    name: String,
+    eventloop: &mut rumqttc::EventLoop,
+    source: AsyncClient,
+    inflight: &Inflight,
+    sinks: HashMap<String, QueuedSink>,
+    labeling: watch::Receiver<Arc<Labeling>>,
+    mls_topic: String,
+    announcements: Arc<Mutex<Announcements>>,
+    reconnect: &mut Reconnect,
+    unlabeled: &mut UnlabeledTopics,
+    audit: Option<Arc<AuditLog>>,
+    metrics: Arc<Metrics>,
+    connected: watch::Sender<bool>,
+    shutdown: watch::Receiver<bool>) -> Result<()> {
+    loop {
+        debug!("source {name} loop");
+        match eventloop.poll().await {
+           Ok(notification) => {
+            match notification {
+                Incoming(Publish(msg)) if *shutdown.borrow() => {
+                        // Not acknowledged, so a persistent session redelivers it after the restart
+                        debug!("Shutting down, ignored message from {name} on {}", msg.topic);
+                },
+                Incoming(Publish(msg)) => {
+                        debug!("Foward Incoming message from {name} = {:?}", msg);
+                        let labeling = labeling.borrow().clone();
+                        // Acknowledged to the source once all sinks accepted it, or right away if it is dropped
+                        let ack = SourceAck::new(&source, &msg);
+                        let unlabeled_before = unlabeled.total();
+                        let routes = metrics.lookup_seconds.time(&[], || labeling.forward(&name, &msg.topic, &msg.payload, unlabeled));
+                        if routes.is_empty() {
+                            debug!("Dropped {} unlabeled messages from {name} so far", unlabeled.total());
+                            metrics.messages.inc(&[Decision::Dropped.as_str(), ""]);
+                            record_logged(audit.as_deref(), Decision::Dropped, &msg.topic, None, None, &msg.payload);
+                        }
+                        // Only messages without a rule on any route are counted as unlabeled
+                        let decision = if unlabeled.total() > unlabeled_before { Decision::Unlabeled } else { Decision::Labeled };
+                        for (route, topic, label, announced) in routes {
+                            metrics.messages.inc(&[decision.as_str(), &label.to_string()]);
+                            record_logged(audit.as_deref(), decision, &topic, Some(label), Some(route.label_key.get_id()), &msg.payload);
+                            let sink = &sinks[&route.sink];
+                            let changed = announcements.lock().unwrap().update(&route.sink, &topic, announced);
+                            if changed {
+                                let label_info = info_msg(&topic, announced, &labeling.info_key)?;
+                                let info_topic = LabeledInfo::retained_topic(&mls_topic, &topic);
+                                sink.push(info_topic, QoS::ExactlyOnce, true, label_info).await?;
+                            }
+                            sink.push_labeled(topic, msg.qos, msg.retain, &msg.payload, label, &route.label_key, ack.clone(), &metrics.sign_seconds).await?;
+                        }
+                },
+                Incoming(ConnAck(_)) => {
+                    info!("subscribe to source {name}");
+                    reconnect.connected();
+                    connected.send_replace(true);
+                    let filters = labeling.borrow().filters(&name);
+                    if !filters.is_empty() {
+                        source.subscribe_many(filters).await?;
+                    }
+                },
+                // The downlink messages written to the source
+                Incoming(PubAck(ack)) => inflight.acknowledged(ack.pkid),
+                Incoming(PubComp(comp)) => inflight.acknowledged(comp.pkid),
+                Outgoing(rumqttc::Outgoing::Publish(pkid)) => inflight.published(pkid),
+                Incoming(incoming) => {
+                    debug!("Source {name} Received Incoming event = {:?}", incoming);
+                },
+                Outgoing(rumqttc::Outgoing::Disconnect) => {
+                    info!("Disconnected from source {name}");
+                    return Ok(());
+                },
+                Outgoing(outgoing) => {
+                    debug!("Source {name} Received Outgoing event = {:?}", outgoing);
+                },
+            }
+           }
+           Err(e) => {
+                connected.send_replace(false);
+                if !reconnect.error() {
+                    return Err(eyre!("Source {name} connection exceeded the error budget of {}", reconnect.budget()));
+                }
+                delay_on_disconnect(e, reconnect).await;
+           }
+       }
+    }
+}
+
+
+/// Publishes the labels of all announced topics again every `info_refresh_secs`, so label_db learns them even
+/// if a retained info was lost, without holding up the sources.
+///
+/// Sinks which are not connected are skipped, they get the infos queued before the outage.
+async fn refresh_task(
+    sinks: HashMap<String, QueuedSink>,
+    connected: HashMap<String, watch::Receiver<bool>>,
+    announcements: Arc<Mutex<Announcements>>,
+    labeling: watch::Receiver<Arc<Labeling>>,
+    mls_topic: String,
+    info_refresh_secs: u64) -> Result<()> {
+    if info_refresh_secs == 0 {
+        return std::future::pending().await;
+    }
+    let period = Duration::from_secs(info_refresh_secs);
+    let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        refresh.tick().await;
+        let announced: Vec<(String, String, Label)> = announcements
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(sink, topic, label)| (sink.to_string(), topic.to_string(), label))
+            .collect();
+        debug!("Re-announce {} topic labels", announced.len());
+        let labeling = labeling.borrow().clone();
+        for (sink, topic, label) in announced {
+            if !*connected[&sink].borrow() {
+                continue;
+            }
+            let info_topic = LabeledInfo::retained_topic(&mls_topic, &topic);
+            if let Err(e) = sinks[&sink].push(info_topic, QoS::ExactlyOnce, true, info_msg(&topic, label, &labeling.info_key)?).await {
+                warn!("Could not re-announce the label of {topic} to sink {sink}: {e:#}");
+            }
+        }
+    }
+}
+
+/// Waits before the event loop reconnects, errors of the MQTT state reconnect right away
+pub(crate) async fn delay_on_disconnect(err: ConnectionError, reconnect: &mut Reconnect) {
+        debug!("{:?}", err);
+        match err {
+            ConnectionError::MqttState(_) => {}
+            ConnectionError::FlushTimeout
+            | ConnectionError::Tls(_)
+            | ConnectionError::NotConnAck(_)
+            | ConnectionError::RequestsDone
+            | ConnectionError::WsConnect(_) => {
+                error!("{:?}", err);
+                reconnect.backoff().await;
+            }
+            ConnectionError::Io(_)
+            | ConnectionError::NetworkTimeout
+            | ConnectionError::ConnectionRefused(_)
+            | ConnectionError::Websocket(_) => {
+                reconnect.backoff().await;
+            }
+        }
+}
+
+pub(crate) fn connect(broker: &BrokerConfig, manual_acks: bool, status: Option<&StatusAnnouncer>) -> Result<(AsyncClient, rumqttc::EventLoop)> {
+    let mut mqttoptions = mls::conf::mqtt_options(&broker.url)?;
+    mqttoptions.set_keep_alive(Duration::from_secs(30));
+    mqttoptions.set_manual_acks(manual_acks);
+    broker.session.apply(&mut mqttoptions);
+    mls::conf::apply_security(&mut mqttoptions, broker.tls.as_ref(), broker.auth.as_ref())?;
+    if let Some(status) = status {
+        status.apply(&mut mqttoptions)?;
+    }
+    if broker.session.expiry_secs.is_some() {
+        warn!("The session expiry of {} is only sent by MQTT v5 connections, the broker decides for MQTT 3", broker.url);
+    }
+    Ok(AsyncClient::new(mqttoptions, 10))
+}
+
+async fn main_loop(cfg: Config, conf_path: PathBuf, labeling: Labeling) -> Result<()> {
+    cfg.check_client_ids()?;
+    let metrics = Arc::new(Metrics::default());
+    let health = Arc::new(Health::default());
+    let audit = match &cfg.audit {
+        Some(audit) => {
+            let log = AuditLog::open(&audit.path)?;
+            let (records, head) = log.head();
+            info!("Audit log {} continues after {records} records at {head}", audit.path.display());
+            Some(Arc::new(log))
+        }
+        None => None,
+    };
+    let downlinks = cfg.downlinks
+        .iter()
+        .map(|downlink| Downlink::load(downlink, &cfg, &metrics))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut sources = HashMap::new();
+    let mut downlink_sources = HashMap::new();
+    let mut source_eventloops = Vec::new();
+    for (name, broker) in &cfg.sources {
+        if broker.label_transport != LabelTransport::Envelope {
+            return Err(eyre!("The label transport of source {name} is only used for sinks"));
+        }
+        info!("source {name} = {}", broker.url);
+        let (client, eventloop) = connect(broker, true, None)?;
+        let inflight = Arc::new(Inflight::default());
+        downlink_sources.insert(name.clone(), DownlinkSource { client: client.clone(), inflight: inflight.clone() });
+        sources.insert(name.clone(), client);
+        source_eventloops.push((name.clone(), eventloop, inflight));
+    }
+    let mut key_ids: Vec<String> = cfg.routes.iter().map(|route| route.label_key.id.clone()).collect();
+    key_ids.push(cfg.info_key.id.clone());
+    key_ids.sort();
+    key_ids.dedup();
+    let mut sinks = HashMap::new();
+    let mut sink_eventloops = Vec::new();
+    for (name, broker) in &cfg.sinks {
+        info!("sink {name} = {} ({:?})", broker.url, broker.label_transport);
+        let status = match &cfg.status {
+            Some(status) => {
+                let client_id = mls::conf::mqtt_options(&broker.url)?.client_id();
+                Some(Arc::new(StatusAnnouncer::new(status, status.key.get_key()?, "proxy", client_id, key_ids.clone())))
+            }
+            None => None,
+        };
+        let (client, eventloop) = Sink::connect(broker, status.as_deref())?;
+        let queue = Arc::new(Queue::open(name, &cfg.queue)?.with_metric(metrics.queue_dropped.clone(), name));
+        sinks.insert(name.clone(), QueuedSink { sink: client, queue });
+        sink_eventloops.push((name.clone(), eventloop, status));
+    }
+
+    let (labeling_tx, labeling) = watch::channel(Arc::new(labeling));
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    // Reload, status, metrics and health tasks stop first, then the sinks are drained before the sources disconnect
+    let mut tasks = JoinSet::new();
+    let mut sink_tasks = JoinSet::new();
+    let mut source_tasks = JoinSet::new();
+    let mut forwarders = Vec::new();
+    let mut inflights = HashMap::new();
+    let mut statuses = HashMap::new();
+    let mut sink_sites = HashMap::new();
+    let mut source_sites = HashMap::new();
+    let announcements = Arc::new(Mutex::new(Announcements::default()));
+    tasks.spawn(reload::reload_task(conf_path, cfg.clone(), sources.clone(), sinks.clone(), labeling_tx, announcements.clone()));
+    tasks.spawn(metrics::metrics_task(cfg.metrics.clone(), metrics.clone(), sinks.clone()));
+    let health_task = mls::health::health_task(cfg.health.clone(), health.clone());
+    tasks.spawn(async move { Ok(health_task.await?) });
+    let mut downlinks: HashMap<String, Vec<Downlink>> = downlinks.into_iter().fold(HashMap::new(), |mut map, downlink| {
+        map.entry(downlink.sink.clone()).or_default().push(downlink);
+        map
+    });
+    for (name, eventloop, status) in sink_eventloops {
+        let QueuedSink { sink, queue } = sinks[&name].clone();
+        let downlinks = downlinks.remove(&name).unwrap_or_default();
+        let connected_tx = health.part(&format!("sink/{name}"));
+        let connected = connected_tx.subscribe();
+        let inflight = Arc::new(Inflight::default());
+        if let Some(status) = &status {
+            tasks.spawn(sink::status_task(name.clone(), sink.clone(), inflight.clone(), status.clone(), connected.clone()));
+            statuses.insert(name.clone(), status.clone());
+        }
+        forwarders.push(sink_tasks.spawn(sink::forward_task(name.clone(), queue, sink.clone(), inflight.clone(), connected.clone())));
+        inflights.insert(name.clone(), inflight.clone());
+        let reconnect = Reconnect::new(cfg.reconnect.clone()).with_metric(metrics.reconnects.clone(), &name);
+        let task = sink_tasks.spawn(sink::sink_task(name.clone(), eventloop, sink, downlink_sources.clone(), downlinks, inflight, connected_tx, reconnect, status, metrics.clone(), shutdown.clone()));
+        sink_sites.insert(name, (connected.clone(), task));
+    }
+    let sinks_connected: HashMap<_, _> = sink_sites.iter().map(|(name, (connected, _))| (name.clone(), connected.clone())).collect();
+    tasks.spawn(refresh_task(sinks.clone(), sinks_connected.clone(), announcements.clone(), labeling.clone(), cfg.mls_topic.clone(), cfg.info_refresh_secs));
+    for (name, eventloop, inflight) in source_eventloops {
+        let source = sources[&name].clone();
+        let reconnect = Reconnect::new(cfg.reconnect.clone()).with_metric(metrics.reconnects.clone(), &name);
+        let connected = health.part(&format!("source/{name}"));
+        let site = connected.subscribe();
+        let task = source_tasks.spawn(source_task(name.clone(), eventloop, source, inflight, sinks.clone(), labeling.clone(), cfg.mls_topic.clone(), announcements.clone(), reconnect, audit.clone(), metrics.clone(), connected, shutdown.clone()));
+        source_sites.insert(name, (site, task));
+    }
+    debug!("task started");
+    // Sources and sinks restart after errors, so the first task to finish ends the proxy
+    let result = tokio::select! {
+        Some(result) = tasks.join_next() => result,
+        Some(result) = sink_tasks.join_next() => result,
+        Some(result) = source_tasks.join_next() => result,
+        signal = mls::shutdown::signal() => {
+            info!("Received {}, shutting down", signal?);
+            health.stop();
+            shutdown_tx.send_replace(true);
+            tasks.shutdown().await;
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(cfg.shutdown_timeout_secs);
+            let connected = sinks_connected;
+            let drain = async {
+                for (name, status) in &statuses {
+                    // A sink which is not connected already got the Last Will
+                    if *connected[name].borrow() {
+                        sinks[name].push(status.topic().into(), QoS::AtLeastOnce, true, status.message(State::Offline)?).await?;
+                    }
+                }
+                drain_sinks(&sinks, &inflights, &connected).await;
+                info!("Flushed the sink queues");
+                Ok::<_, eyre::Report>(())
+            };
+            let result = match tokio::time::timeout_at(deadline, drain).await {
+                Ok(result) => result,
+                Err(_) => {
+                    let queued: usize = sinks.values().map(|sink| sink.queue.len()).sum();
+                    Err(eyre!("Shutdown timed out after {}s with {queued} queued messages", cfg.shutdown_timeout_secs))
+                }
+            };
+            forwarders.iter().for_each(|forwarder| forwarder.abort());
+            for (name, (connected, task)) in &sink_sites {
+                stop_site(&format!("sink {name}"), sinks[name].sink.disconnect(), connected, task).await;
+            }
+            join_stopped(&mut sink_tasks, DISCONNECT_TIMEOUT).await;
+            // The sources disconnect last, also after a timeout, so they deliver the messages which were not acknowledged again
+            for (name, (connected, task)) in &source_sites {
+                let disconnect = async { Ok(sources[name].disconnect().await?) };
+                stop_site(&format!("source {name}"), disconnect, connected, task).await;
+            }
+            join_stopped(&mut source_tasks, DISCONNECT_TIMEOUT).await;
+            return result;
+        }
+    };
+    result?
+}
+
+/// Time the sources and sinks get to disconnect once the sink queues are flushed or the shutdown timed out
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits until every sink acknowledged its queued messages.
+///
+/// A persisted queue keeps the messages for the next start, so a sink with one is drained once it is not connected.
+async fn drain_sinks(sinks: &HashMap<String, QueuedSink>, inflights: &HashMap<String, Arc<Inflight>>, connected: &HashMap<String, watch::Receiver<bool>>) {
+    let drained = |name: &String, sink: &QueuedSink| {
+        (sink.queue.len() == 0 && inflights[name].is_empty()) || (sink.queue.is_persisted() && !*connected[name].borrow())
+    };
+    while !sinks.iter().all(|(name, sink)| drained(name, sink)) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Disconnects a connected source or sink, the loop of one which is not connected would never send the disconnect so it is aborted
+async fn stop_site(site: &str, disconnect: impl std::future::Future<Output = Result<()>>, connected: &watch::Receiver<bool>, task: &AbortHandle) {
+    if !*connected.borrow() {
+        debug!("Stopping {site}, which is not connected");
+        task.abort();
+        return;
+    }
+    // Fails if the loop of the site already stopped
+    if let Err(e) = disconnect.await {
+        debug!("Could not disconnect {site}: {e}");
+    }
+}
+
+/// Waits for the tasks which end after a disconnect, errors are only logged since the proxy stops anyway
+async fn join_stopped(tasks: &mut JoinSet<Result<()>>, timeout: Duration) {
+    let joined = async {
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Err(e)) => warn!("Task failed while shutting down: {e:#}"),
+                Err(e) if !e.is_cancelled() => warn!("Task failed while shutting down: {e}"),
+                _ => {}
+            }
+        }
+    };
+    if tokio::time::timeout(timeout, joined).await.is_err() {
+        warn!("{} tasks did not stop within {}s", tasks.len(), timeout.as_secs());
+        tasks.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_failed_site() {
+        let metrics = Metrics::default();
+        let mut reconnect = Reconnect::new(mls::conf::ReconnectConfig { max_errors: 1, jitter: 0.0, ..Default::default() });
+        let (connected, _) = watch::channel(true);
+        let (shutdown_tx, mut shutdown) = watch::channel(false);
+        assert!(reconnect.error());
+        assert!(!reconnect.error());
+
+        let started = tokio::time::Instant::now();
+        assert!(restart_site("Source edge", "edge", eyre!("failed"), &mut reconnect, &connected, &metrics, &mut shutdown).await);
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        assert!(!*connected.borrow());
+        assert_eq!(metrics.restarts.get(&["edge"]), 1);
+        // The restarted loop has a new error budget
+        assert!(reconnect.error());
+
+        shutdown_tx.send_replace(true);
+        assert!(!restart_site("Source edge", "edge", eyre!("failed"), &mut reconnect, &connected, &metrics, &mut shutdown).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refresh_announcements() {
+        use ed25519_dalek::SigningKey;
+
+        let (client, _eventloop) = AsyncClient::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 10);
+        let queue = Arc::new(Queue::open("fog", &config::QueueConfig::default()).unwrap());
+        let sinks = HashMap::from([("fog".to_string(), QueuedSink { sink: Sink::V3(client), queue: queue.clone() })]);
+        let announcements = Arc::new(Mutex::new(Announcements::default()));
+        announcements.lock().unwrap().update("fog", "sensors/a", 2);
+        let labeling = Labeling { routes: Vec::new(), info_key: mls::Key::new(SigningKey::from_bytes(&[2; 32]), "info".into()) };
+        let (_labeling_tx, labeling) = watch::channel(Arc::new(labeling));
+        let (connected_tx, connected) = watch::channel(false);
+        let connected = HashMap::from([("fog".to_string(), connected)]);
+        tokio::spawn(refresh_task(sinks, connected, announcements, labeling, "mls/info".into(), 300));
+
+        // The sink is down, it already got the info before
+        tokio::time::sleep(Duration::from_secs(301)).await;
+        assert_eq!(queue.len(), 0);
+        connected_tx.send_replace(true);
+        tokio::time::sleep(Duration::from_secs(298)).await;
+        assert_eq!(queue.len(), 0);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let (_, msg, _) = queue.peek().await;
+        assert_eq!((msg.topic.as_str(), msg.retain), ("mls/info/sensors/a", true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_on_shutdown() {
+        let dir = std::env::temp_dir().join(format!("mls_drain_test_{}", std::process::id()));
+        let persisted = config::QueueConfig { dir: Some(dir.clone()), ..Default::default() };
+        let sink = |queue: &config::QueueConfig| {
+            let (client, _eventloop) = AsyncClient::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 10);
+            let queue = Arc::new(Queue::open("fog", queue).unwrap());
+            QueuedSink { sink: Sink::V3(client), queue }
+        };
+        let (cloud, fog) = (sink(&config::QueueConfig::default()), sink(&persisted));
+        let sinks = HashMap::from([("cloud".to_string(), cloud.clone()), ("fog".to_string(), fog.clone())]);
+        for sink in sinks.values() {
+            sink.push("mls/info/a".into(), QoS::AtLeastOnce, true, vec![1]).await.unwrap();
+        }
+        let inflights = HashMap::from([("cloud".to_string(), Arc::default()), ("fog".to_string(), Arc::default())]);
+        let (cloud_tx, cloud_connected) = watch::channel(false);
+        let (_fog_tx, fog_connected) = watch::channel(false);
+        let connected = HashMap::from([("cloud".to_string(), cloud_connected), ("fog".to_string(), fog_connected)]);
+
+        // The messages of a sink which is down are lost without a persisted queue
+        assert!(tokio::time::timeout(Duration::from_secs(30), drain_sinks(&sinks, &inflights, &connected)).await.is_err());
+        cloud_tx.send_replace(true);
+        let drain = tokio::spawn(async move { drain_sinks(&sinks, &inflights, &connected).await });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!drain.is_finished());
+        // The persisted queue of the sink which is down is kept, only the connected sink is waited for
+        let (seq, _, _) = cloud.queue.peek().await;
+        cloud.queue.pop(seq).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), drain).await.unwrap().unwrap();
+        assert_eq!(fog.queue.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_disconnected_site() {
+        let (connected_tx, connected) = watch::channel(false);
+        let mut tasks: JoinSet<Result<()>> = JoinSet::new();
+        let task = tasks.spawn(std::future::pending());
+        // The loop of a site which is not connected would never send the disconnect
+        stop_site("sink fog", async { panic!("disconnected") }, &connected, &task).await;
+        join_stopped(&mut tasks, DISCONNECT_TIMEOUT).await;
+        assert!(tasks.is_empty());
+
+        connected_tx.send_replace(true);
+        let task = tasks.spawn(std::future::pending());
+        let (disconnect_tx, disconnect) = tokio::sync::oneshot::channel();
+        stop_site("sink fog", async {
+            disconnect_tx.send(()).unwrap();
+            Ok(())
+        }, &connected, &task).await;
+        disconnect.await.unwrap();
+        assert!(!task.is_finished());
+        task.abort();
+    }
+}
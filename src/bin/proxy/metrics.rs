@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use eyre::Result;
+use log::info;
+
+use mls::{
+    conf::MetricsConfig,
+    http::{self, Request, Response},
+    metrics::{Counter, Gauge, Histogram, Registry, LATENCY_BUCKETS},
+};
+
+use crate::sink::QueuedSink;
+
+/// Counters of the proxy, collected even without a metrics endpoint
+pub struct Metrics {
+    registry: Registry,
+    /// Messages by audit decision and label, dropped messages have an empty label
+    pub messages: Arc<Counter>,
+    pub signature_failures: Arc<Counter>,
+    /// Messages by source and topic for the recently seen topics without a label
+    pub unlabeled: Arc<Counter>,
+    pub dropped_downlinks: Arc<Counter>,
+    pub reconnects: Arc<Counter>,
+    /// Restarts of a source or sink after it exceeded its error budget or failed otherwise
+    pub restarts: Arc<Counter>,
+    queued: Arc<Gauge>,
+    /// Messages a full queue dropped, they are acknowledged to the source
+    pub queue_dropped: Arc<Counter>,
+    pub sign_seconds: Arc<Histogram>,
+    pub lookup_seconds: Arc<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::default();
+        Metrics {
+            messages: registry.counter("mls_proxy_messages_total", "Messages from the sources by decision and label", &["decision", "label"]),
+            unlabeled: registry.counter("mls_proxy_unlabeled_messages_total", "Messages on topics without a label, by source and recently seen topic", &["source", "topic"]),
+            signature_failures: registry.counter("mls_proxy_signature_failures_total", "Downlink messages with an unknown key or a signature which failed to verify", &["key_id"]),
+            dropped_downlinks: registry.counter("mls_proxy_downlink_dropped_total", "Verified downlink messages dropped since the request queue of the source was full", &["source"]),
+            reconnects: registry.counter("mls_proxy_reconnects_total", "Reconnects of the source and sink connections", &["broker"]),
+            restarts: registry.counter("mls_proxy_restarts_total", "Restarts of a source or sink which failed", &["broker"]),
+            queued: registry.gauge("mls_proxy_queued_messages", "Messages waiting in the queue of a sink", &["sink"]),
+            queue_dropped: registry.counter("mls_proxy_queue_dropped_total", "Messages dropped by the overflow policy of a full sink queue", &["sink"]),
+            sign_seconds: registry.histogram("mls_proxy_sign_seconds", "Seconds to sign a labeled message", &[], LATENCY_BUCKETS),
+            lookup_seconds: registry.histogram("mls_proxy_lookup_seconds", "Seconds to find the routes and labels of a message", &[], LATENCY_BUCKETS),
+            registry,
+        }
+    }
+}
+
+async fn handle_metrics(req: Request, metrics: Arc<Metrics>, sinks: Arc<HashMap<String, QueuedSink>>) -> Response {
+    if req.path != "/metrics" {
+        return Response::not_found();
+    }
+    for (name, sink) in sinks.iter() {
+        metrics.queued.set(&[name], sink.queue.len() as f64);
+    }
+    metrics.registry.response()
+}
+
+/// Serves `/metrics` for Prometheus
+pub async fn metrics_task(cfg: Option<MetricsConfig>, metrics: Arc<Metrics>, sinks: HashMap<String, QueuedSink>) -> Result<()> {
+    let Some(cfg) = cfg else {
+        return std::future::pending().await;
+    };
+    info!("metrics endpoint = {}", cfg.listen);
+    let sinks = Arc::new(sinks);
+    http::serve(cfg.listen, move |req| handle_metrics(req, metrics.clone(), sinks.clone())).await?;
+    Ok(())
+}
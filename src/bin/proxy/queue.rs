@@ -0,0 +1,454 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use eyre::Result;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use mls::metrics::Counter;
+
+use crate::config::{OverflowPolicy, QueueConfig};
+use crate::sink::SourceAck;
+
+/// A labeled message ready to be published to a sink
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedMsg {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+    /// Only sent to MQTT v5 sinks
+    #[serde(default)]
+    pub user_properties: Vec<(String, String)>,
+    /// Queued even if the queue is full and never dropped for a newer message, for the label infos
+    /// which the announcements remember as sent, only a newer info on the same topic replaces it
+    #[serde(default)]
+    pub keep: bool,
+}
+
+impl QueuedMsg {
+    fn size(&self) -> u64 {
+        let properties: usize = self.user_properties.iter().map(|(k, v)| k.len() + v.len()).sum();
+        (self.topic.len() + self.payload.len() + properties) as u64
+    }
+}
+
+struct Entry {
+    seq: u64,
+    msg: QueuedMsg,
+    /// Acknowledges the message to the source once every sink accepted it, restored messages have none
+    ack: Option<Arc<SourceAck>>,
+}
+
+struct Inner {
+    entries: VecDeque<Entry>,
+    bytes: u64,
+    next_seq: u64,
+    dropped: u64,
+    /// The message `forward_task` peeked and is publishing, it is not evicted
+    sending: Option<u64>,
+}
+
+/// Bounded queue of the messages for one sink, optionally persisted with one file per message.
+///
+/// The files are named by sequence number, so the queue is restored in order after a restart.
+/// They are written and removed outside of the lock of the queue, so a slow disk only holds up the
+/// pushes and not the sink.
+pub struct Queue {
+    inner: Mutex<Inner>,
+    /// Pushes one message at a time, so the messages are queued in the order of their sequence numbers
+    writer: tokio::sync::Mutex<()>,
+    changed: Notify,
+    dir: Option<PathBuf>,
+    max_messages: usize,
+    max_bytes: u64,
+    overflow: OverflowPolicy,
+    /// Counter of the dropped messages and the sink label
+    metric: Option<(Arc<Counter>, String)>,
+}
+
+fn file_name(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{seq:020}.cbor"))
+}
+
+fn load(dir: &Path) -> Result<Vec<(u64, QueuedMsg)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            // A crash while persisting, the message was never acknowledged to its source which delivers it again
+            warn!("Removing the partly written queued message {}", path.display());
+            fs::remove_file(&path)?;
+            continue;
+        }
+        let Some(seq) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".cbor"))
+            .and_then(|n| n.parse().ok())
+        else {
+            continue;
+        };
+        match fs::read(&path).map_err(eyre::Report::from).and_then(|bytes| Ok(ciborium::de::from_reader(&bytes[..])?)) {
+            Ok(msg) => entries.push((seq, msg)),
+            Err(e) => {
+                warn!("Removing unreadable queued message {}: {e}", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    entries.sort_by_key(|(seq, _)| *seq);
+    Ok(entries)
+}
+
+impl Queue {
+    /// Creates the queue, with a `cfg.dir` the messages left in `<dir>/<name>` are restored
+    pub fn open(name: &str, cfg: &QueueConfig) -> Result<Self> {
+        let dir = cfg.dir.as_ref().map(|dir| dir.join(name));
+        let mut inner = Inner {
+            entries: VecDeque::new(),
+            bytes: 0,
+            next_seq: 0,
+            dropped: 0,
+            sending: None,
+        };
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir)?;
+            for (seq, msg) in load(dir)? {
+                inner.bytes += msg.size();
+                inner.next_seq = seq + 1;
+                inner.entries.push_back(Entry { seq, msg, ack: None });
+            }
+            if !inner.entries.is_empty() {
+                warn!("Restored {} queued messages for sink {name}", inner.entries.len());
+            }
+        }
+        Ok(Queue {
+            inner: Mutex::new(inner),
+            writer: tokio::sync::Mutex::new(()),
+            changed: Notify::new(),
+            dir,
+            max_messages: cfg.max_messages,
+            max_bytes: cfg.max_bytes,
+            overflow: cfg.overflow,
+            metric: None,
+        })
+    }
+
+    /// Counts the dropped messages in `dropped`, labeled with `sink`
+    pub fn with_metric(mut self, dropped: Arc<Counter>, sink: &str) -> Self {
+        self.metric = Some((dropped, sink.into()));
+        self
+    }
+
+    /// Counts a dropped message, its ack is released so the source gets it once the other sinks accepted the message
+    fn dropped(&self, inner: &mut Inner) {
+        inner.dropped += 1;
+        if let Some((dropped, sink)) = &self.metric {
+            dropped.inc(&[sink]);
+        }
+    }
+
+    fn is_full(&self, inner: &Inner, size: u64) -> bool {
+        !inner.entries.is_empty() && (inner.entries.len() >= self.max_messages || inner.bytes + size > self.max_bytes)
+    }
+
+    /// Removes the oldest messages which may be dropped until `size` fits, returns their sequence numbers
+    fn evict(&self, inner: &mut Inner, size: u64) -> Vec<u64> {
+        let mut evicted = Vec::new();
+        while self.is_full(inner, size) {
+            let sending = inner.sending;
+            let Some(index) = inner.entries.iter().position(|entry| !entry.msg.keep && Some(entry.seq) != sending) else {
+                break;
+            };
+            let entry = inner.entries.remove(index).expect("found above");
+            inner.bytes -= entry.msg.size();
+            self.dropped(inner);
+            evicted.push(entry.seq);
+        }
+        evicted
+    }
+
+    /// Removes the queued infos on the topic of `msg`, which it supersedes, returns their sequence numbers
+    fn supersede(&self, inner: &mut Inner, msg: &QueuedMsg) -> Vec<u64> {
+        let sending = inner.sending;
+        let mut superseded = Vec::new();
+        inner.entries.retain(|entry| {
+            let replaced = entry.msg.keep && entry.msg.topic == msg.topic && Some(entry.seq) != sending;
+            if replaced {
+                superseded.push((entry.seq, entry.msg.size()));
+            }
+            !replaced
+        });
+        superseded.into_iter().map(|(seq, size)| {
+            inner.bytes -= size;
+            seq
+        }).collect()
+    }
+
+    async fn remove_files(&self, seqs: Vec<u64>) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            for seq in seqs {
+                tokio::fs::remove_file(file_name(dir, seq)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a message, a full queue is handled by the overflow policy.
+    ///
+    /// An info replaces the queued info on its topic, so infos published again and again while the sink is down
+    /// do not pile up.
+    pub async fn push(&self, msg: QueuedMsg, ack: Option<Arc<SourceAck>>) -> Result<()> {
+        let size = msg.size();
+        let _writer = self.writer.lock().await;
+        let seq = loop {
+            let changed = self.changed.notified();
+            let mut evicted = Vec::new();
+            let seq = {
+                let mut inner = self.inner.lock().unwrap();
+                if msg.keep {
+                    evicted = self.supersede(&mut inner, &msg);
+                }
+                if self.is_full(&inner, size) && self.overflow == OverflowPolicy::DropOldest {
+                    let dropped = self.evict(&mut inner, size);
+                    if !dropped.is_empty() {
+                        warn!("Queue full, dropped the oldest messages, {} dropped so far", inner.dropped);
+                    }
+                    evicted.extend(dropped);
+                }
+                if !self.is_full(&inner, size) || (msg.keep && self.overflow != OverflowPolicy::Block) {
+                    inner.next_seq += 1;
+                    Some(Some(inner.next_seq - 1))
+                } else if self.overflow == OverflowPolicy::Block {
+                    debug!("Queue full, waiting for the sink");
+                    None
+                } else {
+                    self.dropped(&mut inner);
+                    warn!("Queue full, dropped message on {}, {} dropped so far", msg.topic, inner.dropped);
+                    Some(None)
+                }
+            };
+            self.remove_files(evicted).await?;
+            match seq {
+                Some(seq) => break seq,
+                None => changed.await,
+            }
+        };
+        let Some(seq) = seq else {
+            return Ok(());
+        };
+        if let Some(dir) = &self.dir {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&msg, &mut bytes)?;
+            let (tmp, path) = (dir.join(format!("{seq:020}.tmp")), file_name(dir, seq));
+            tokio::task::spawn_blocking(move || {
+                fs::write(&tmp, bytes)?;
+                fs::rename(tmp, path)
+            })
+            .await??;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.bytes += size;
+        inner.entries.push_back(Entry { seq, msg, ack });
+        self.changed.notify_waiters();
+        Ok(())
+    }
+
+    /// Waits for the oldest message and returns it with its sequence number without removing it.
+    ///
+    /// The message is not evicted until it is popped, it is being published.
+    pub async fn peek(&self) -> (u64, QueuedMsg, Option<Arc<SourceAck>>) {
+        loop {
+            let changed = self.changed.notified();
+            let front = {
+                let mut inner = self.inner.lock().unwrap();
+                let front = inner.entries.front().map(|front| (front.seq, front.msg.clone(), front.ack.clone()));
+                inner.sending = front.as_ref().map(|(seq, _, _)| *seq);
+                front
+            };
+            if let Some(front) = front {
+                return front;
+            }
+            changed.await;
+        }
+    }
+
+    /// Removes the message `seq` after it was published, unless it was dropped in the meantime
+    pub async fn pop(&self, seq: u64) -> Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.sending == Some(seq) {
+                inner.sending = None;
+            }
+            if inner.entries.front().map(|front| front.seq) != Some(seq) {
+                return Ok(());
+            }
+            if let Some(entry) = inner.entries.pop_front() {
+                inner.bytes -= entry.msg.size();
+            }
+            self.changed.notify_waiters();
+        }
+        self.remove_files(vec![seq]).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// True if the messages are kept on disk for the next start
+    pub fn is_persisted(&self) -> bool {
+        self.dir.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(topic: &str) -> QueuedMsg {
+        QueuedMsg {
+            topic: topic.into(),
+            qos: 1,
+            retain: false,
+            payload: vec![0; 10],
+            user_properties: Vec::new(),
+            keep: false,
+        }
+    }
+
+    fn config(dir: Option<PathBuf>, overflow: OverflowPolicy) -> QueueConfig {
+        QueueConfig {
+            dir,
+            max_messages: 3,
+            max_bytes: 1024,
+            overflow,
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        let queue = Queue::open("fog", &config(None, OverflowPolicy::DropOldest)).unwrap();
+        for topic in ["a", "b", "c", "d"] {
+            queue.push(msg(topic), None).await.unwrap();
+        }
+        assert_eq!(queue.len(), 3);
+        let (seq, front, _) = queue.peek().await;
+        assert_eq!((seq, front.topic.as_str()), (1, "b"));
+
+        let queue = Queue::open("fog", &config(None, OverflowPolicy::DropNewest)).unwrap();
+        for topic in ["a", "b", "c", "d"] {
+            queue.push(msg(topic), None).await.unwrap();
+        }
+        assert_eq!(queue.peek().await.1.topic, "a");
+        // Popping a message which is no longer at the front does nothing
+        queue.pop(5).await.unwrap();
+        queue.pop(0).await.unwrap();
+        assert_eq!(queue.peek().await.1.topic, "b");
+        assert_eq!(queue.len(), 2);
+
+        let queue = std::sync::Arc::new(Queue::open("fog", &config(None, OverflowPolicy::Block)).unwrap());
+        for topic in ["a", "b", "c"] {
+            queue.push(msg(topic), None).await.unwrap();
+        }
+        let blocked = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(msg("d"), None).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+        queue.pop(queue.peek().await.0).await.unwrap();
+        blocked.await.unwrap().unwrap();
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn sending_not_evicted() {
+        let dropped = mls::metrics::Registry::default().counter("dropped", "", &["sink"]);
+        let queue = Queue::open("fog", &config(None, OverflowPolicy::DropOldest)).unwrap().with_metric(dropped.clone(), "fog");
+        for topic in ["a", "b", "c"] {
+            queue.push(msg(topic), None).await.unwrap();
+        }
+        // The forwarder publishes "a", so "b" is dropped for "d"
+        let (seq, _, _) = queue.peek().await;
+        queue.push(msg("d"), None).await.unwrap();
+        assert_eq!(dropped.get(&["fog"]), 1);
+        queue.pop(seq).await.unwrap();
+        assert_eq!(queue.peek().await.1.topic, "c");
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn keep_infos() {
+        let info = |topic: &str| QueuedMsg { keep: true, ..msg(topic) };
+        for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+            let queue = Queue::open("fog", &config(None, overflow)).unwrap();
+            queue.push(info("mls/info/a"), None).await.unwrap();
+            for topic in ["b", "c", "d"] {
+                queue.push(msg(topic), None).await.unwrap();
+            }
+            // A full queue still takes infos, over its limit if only infos are left to drop
+            queue.push(info("mls/info/e"), None).await.unwrap();
+            queue.push(info("mls/info/f"), None).await.unwrap();
+            let mut topics = Vec::new();
+            while queue.len() > 0 {
+                let (seq, front, _) = queue.peek().await;
+                topics.push(front.topic);
+                queue.pop(seq).await.unwrap();
+            }
+            let expected = match overflow {
+                OverflowPolicy::DropOldest => vec!["mls/info/a", "mls/info/e", "mls/info/f"],
+                _ => vec!["mls/info/a", "b", "c", "mls/info/e", "mls/info/f"],
+            };
+            assert_eq!(topics, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn infos_superseded() {
+        let dir = std::env::temp_dir().join(format!("mls_supersede_test_{}", std::process::id()));
+        let info = |topic: &str, payload: u8| QueuedMsg { keep: true, payload: vec![payload], ..msg(topic) };
+        let queue = Queue::open("fog", &config(Some(dir.clone()), OverflowPolicy::DropOldest)).unwrap();
+        queue.push(info("mls/info/a", 1), None).await.unwrap();
+        queue.push(msg("b"), None).await.unwrap();
+        // Refreshes of the info while the sink is down replace the queued one
+        for payload in 2..100 {
+            queue.push(info("mls/info/a", payload), None).await.unwrap();
+        }
+        assert_eq!(queue.len(), 2);
+        assert_eq!(fs::read_dir(dir.join("fog")).unwrap().count(), 2);
+        let (seq, front, _) = queue.peek().await;
+        assert_eq!(front.topic, "b");
+        queue.pop(seq).await.unwrap();
+        assert_eq!(queue.peek().await.1, info("mls/info/a", 99));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn persisted_in_order() {
+        let dir = std::env::temp_dir().join(format!("mls_queue_test_{}", std::process::id()));
+        let cfg = config(Some(dir.clone()), OverflowPolicy::DropOldest);
+        {
+            let queue = Queue::open("fog", &cfg).unwrap();
+            for topic in ["a", "b", "c"] {
+                queue.push(msg(topic), None).await.unwrap();
+            }
+            queue.pop(0).await.unwrap();
+        }
+        fs::write(dir.join("fog").join(format!("{:020}.cbor", 7)), b"garbage").unwrap();
+        fs::write(dir.join("fog").join(format!("{:020}.tmp", 8)), b"partly").unwrap();
+        let queue = Queue::open("fog", &cfg).unwrap();
+        assert_eq!(queue.len(), 2);
+        let (seq, front, _) = queue.peek().await;
+        assert_eq!((seq, front), (1, msg("b")));
+        queue.pop(1).await.unwrap();
+        queue.push(msg("d"), None).await.unwrap();
+        let mut files: Vec<_> = fs::read_dir(dir.join("fog")).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, vec![format!("{:020}.cbor", 2), format!("{:020}.cbor", 3)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
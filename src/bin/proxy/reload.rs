@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use eyre::{eyre, Result};
+use log::{error, info, warn};
+use mls::LabeledInfo;
+use rumqttc::{AsyncClient, QoS};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::labeling::{diff_filters, Announcements, Labeling};
+use crate::sink::QueuedSink;
+
+/// Clears the retained infos of the announced topics which no route labels anymore, so label_db and
+/// new subscribers do not learn a label the proxy stopped using. Returns the number of cleared topics.
+///
+/// Topics which were forgotten by the announcements keep their retained info.
+async fn clear_removed(labeling: &Labeling, mls_topic: &str, sinks: &HashMap<String, QueuedSink>, announcements: &Mutex<Announcements>) -> usize {
+    let removed: Vec<(String, String)> = {
+        let mut announcements = announcements.lock().unwrap();
+        let removed: Vec<_> = announcements
+            .iter()
+            .filter(|(sink, topic, _)| !labeling.announces(sink, topic))
+            .map(|(sink, topic, _)| (sink.to_string(), topic.to_string()))
+            .collect();
+        for (sink, topic) in &removed {
+            announcements.remove(sink, topic);
+        }
+        removed
+    };
+    for (sink, topic) in &removed {
+        info!("Clearing the retained info of {topic} on sink {sink}, no route labels it anymore");
+        if let Err(e) = sinks[sink].push(LabeledInfo::retained_topic(mls_topic, topic), QoS::ExactlyOnce, true, Vec::new()).await {
+            error!("Could not clear the retained info of {topic} on sink {sink}: {e:#}");
+        }
+    }
+    removed.len()
+}
+
+async fn reload(
+    conf_path: &Path,
+    running: &Config,
+    sources: &HashMap<String, AsyncClient>,
+    sinks: &HashMap<String, QueuedSink>,
+    labeling: &watch::Sender<Arc<Labeling>>,
+    announcements: &Mutex<Announcements>,
+) -> Result<()> {
+    // confy would write a default config in place of a missing file
+    if !conf_path.exists() {
+        return Err(eyre!("The config file {} does not exist", conf_path.display()));
+    }
+    let cfg = confy::load_path::<Config>(conf_path)?.migrate()?;
+    let new_labeling = Labeling::load(&cfg)?;
+    if let Some(route) = cfg.routes.iter().find(|route| !sources.contains_key(&route.source) || !running.sinks.contains_key(&route.sink)) {
+        return Err(eyre!("The route {} -> {} uses a broker which is not connected, adding brokers needs a restart", route.source, route.sink));
+    }
+    if cfg.sources != running.sources || cfg.sinks != running.sinks || cfg.mls_topic != running.mls_topic || cfg.downlinks != running.downlinks
+        || cfg.reconnect != running.reconnect || cfg.status != running.status || cfg.audit != running.audit || cfg.metrics != running.metrics || cfg.health != running.health {
+        warn!("Changes of sources, sinks, mls_topic, downlinks, reconnect, status, audit, metrics and health need a restart of the proxy");
+    }
+    let changes: Vec<_> = sources
+        .iter()
+        .map(|(name, source)| (name, source, diff_filters(&labeling.borrow().topics(name), &new_labeling.topics(name))))
+        .collect();
+    // The new labeling applies even if a subscription change fails, a source subscribes to its filters on every connect
+    let new_labeling = Arc::new(new_labeling);
+    labeling.send_replace(new_labeling.clone());
+    clear_removed(&new_labeling, &running.mls_topic, sinks, announcements).await;
+    let mut failed = Vec::new();
+    for (name, source, (added, removed)) in changes {
+        let subscribed = async {
+            if !added.is_empty() {
+                source.subscribe_many(added.iter().map(|(f, qos)| rumqttc::SubscribeFilter::new(f.clone(), *qos))).await?;
+            }
+            for filter in &removed {
+                source.unsubscribe(filter.clone()).await?;
+            }
+            Ok::<_, rumqttc::ClientError>(())
+        };
+        match subscribed.await {
+            Ok(()) if !added.is_empty() || !removed.is_empty() => {
+                info!("Reloaded config, subscribed to {added:?}, unsubscribed from {removed:?} on source {name}");
+            }
+            Ok(()) => {}
+            Err(e) => {
+                error!("Changing the subscriptions of source {name} failed, they are renewed on its next connect: {e}");
+                failed.push(name.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(eyre!("Reloaded the labels, but the subscriptions of {} are not updated yet", failed.join(", ")));
+    }
+    info!("Reloaded config");
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the config on SIGHUP or when the file changes, a config which fails to load is ignored
+pub async fn reload_task(
+    conf_path: PathBuf,
+    running: Config,
+    sources: HashMap<String, AsyncClient>,
+    sinks: HashMap<String, QueuedSink>,
+    labeling: watch::Sender<Arc<Labeling>>,
+    announcements: Arc<Mutex<Announcements>>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let poll_interval = Duration::from_secs(running.config_poll_secs.max(1));
+    let mut last_modified = modified(&conf_path);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading config");
+            }
+            _ = tokio::time::sleep(poll_interval), if running.config_poll_secs > 0 => {
+                let current = modified(&conf_path);
+                if current == last_modified {
+                    continue;
+                }
+                info!("Config file changed, reloading");
+            }
+        }
+        last_modified = modified(&conf_path);
+        if let Err(e) = reload(&conf_path, &running, &sources, &sinks, &labeling, &announcements).await {
+            error!("Reloading the config failed: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use mls::Key;
+
+    #[tokio::test]
+    async fn missing_config() {
+        let path = std::env::temp_dir().join(format!("mls_reload_test_{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let labeling = Labeling {
+            routes: Vec::new(),
+            info_key: Key::new(SigningKey::from_bytes(&[2; 32]), "info".into()),
+        };
+        let (labeling, _) = watch::channel(Arc::new(labeling));
+        let announcements = Mutex::new(Announcements::default());
+        assert!(reload(&path, &Config::default(), &HashMap::new(), &HashMap::new(), &labeling, &announcements).await.is_err());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn clear_removed_topics() {
+        use crate::config::UnlabeledPolicy;
+        use crate::queue::Queue;
+        use crate::sink::Sink;
+        use mls::topicdb::TopicDB;
+
+        let mut rules = TopicDB::new();
+        rules.insert("sensors/#", 2);
+        let labeling = Labeling {
+            routes: vec![crate::labeling::Route {
+                source: "edge".into(),
+                sink: "fog".into(),
+                subscriptions: Default::default(),
+                rules,
+                content: Vec::new(),
+                unlabeled: UnlabeledPolicy::Drop,
+                label_key: Key::new(SigningKey::from_bytes(&[1; 32]), "label".into()),
+            }],
+            info_key: Key::new(SigningKey::from_bytes(&[2; 32]), "info".into()),
+        };
+        let (client, _eventloop) = AsyncClient::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 10);
+        let queue = Arc::new(Queue::open("fog", &crate::config::QueueConfig::default()).unwrap());
+        let sinks = HashMap::from([("fog".to_string(), QueuedSink { sink: Sink::V3(client), queue: queue.clone() })]);
+        let announcements = Mutex::new(Announcements::default());
+        announcements.lock().unwrap().update("fog", "sensors/a", 2);
+        announcements.lock().unwrap().update("fog", "old/b", 1);
+
+        assert_eq!(clear_removed(&labeling, "mls/info", &sinks, &announcements).await, 1);
+        let (_, msg, _) = queue.peek().await;
+        assert_eq!((msg.topic.as_str(), msg.retain, msg.payload.is_empty()), ("mls/info/old/b", true, true));
+        let announced: Vec<_> = announcements.lock().unwrap().iter().map(|(_, topic, _)| topic.to_string()).collect();
+        assert_eq!(announced, vec!["sensors/a".to_string()]);
+    }
+}
@@ -0,0 +1,743 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::{eyre, Result};
+use log::{debug, error, info, warn};
+use rumqttc::v5::{self, mqttbytes::v5::{Packet as PacketV5, PublishProperties}};
+use rumqttc::{AsyncClient, Event::{Incoming, Outgoing}, Packet::{ConnAck, PubAck, PubComp, Publish}, QoS};
+
+use tokio::sync::watch;
+
+use mls::{metrics::Histogram, reconnect::Reconnect, status::{State, StatusAnnouncer}, Key, Label};
+
+use crate::config::{BrokerConfig, LabelTransport};
+use crate::{delay_on_disconnect, restart_site};
+use crate::downlink::Downlink;
+use crate::labeling::label_msg;
+use crate::metrics::Metrics;
+use crate::queue::{QueuedMsg, Queue};
+
+/// A connection to a sink, MQTT v5 sinks get the label in user properties
+#[derive(Clone)]
+pub enum Sink {
+    V3(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+/// The event loop belonging to a `Sink`
+pub enum SinkEventLoop {
+    V3(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+fn qos_v5(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn qos_v3(qos: v5::mqttbytes::QoS) -> QoS {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => QoS::AtMostOnce,
+        v5::mqttbytes::QoS::AtLeastOnce => QoS::AtLeastOnce,
+        v5::mqttbytes::QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+impl Sink {
+    /// Connects with the `offline` status of `status` as Last Will
+    pub fn connect(broker: &BrokerConfig, status: Option<&StatusAnnouncer>) -> Result<(Sink, SinkEventLoop)> {
+        match broker.label_transport {
+            LabelTransport::Envelope => {
+                // Downlink messages are acknowledged once the source accepted them
+                let (client, eventloop) = crate::connect(broker, true, status)?;
+                Ok((Sink::V3(client), SinkEventLoop::V3(Box::new(eventloop))))
+            }
+            LabelTransport::UserProperties => {
+                let mut mqttoptions = mls::conf::mqtt_options_v5(&broker.url)?;
+                mqttoptions.set_keep_alive(Duration::from_secs(30));
+                broker.session.apply_v5(&mut mqttoptions);
+                mqttoptions.set_manual_acks(true);
+                mls::conf::apply_security_v5(&mut mqttoptions, broker.tls.as_ref(), broker.auth.as_ref())?;
+                if let Some(status) = status {
+                    status.apply_v5(&mut mqttoptions)?;
+                }
+                let (client, eventloop) = v5::AsyncClient::new(mqttoptions, 10);
+                Ok((Sink::V5(client), SinkEventLoop::V5(Box::new(eventloop))))
+            }
+        }
+    }
+
+    /// Signs the payload with its label in the format of the sink
+    pub fn prepare_labeled(&self, topic: String, qos: QoS, retain: bool, payload: &[u8], label: Label, label_key: &Key) -> Result<QueuedMsg> {
+        let (payload, user_properties) = match self {
+            Sink::V3(_) => (label_msg(payload, label, &topic, label_key)?, Vec::new()),
+            Sink::V5(_) => (payload.to_vec(), label_key.sign_properties(payload, label, &topic)?.to_user_properties()),
+        };
+        Ok(QueuedMsg {
+            topic,
+            qos: qos as u8,
+            retain,
+            payload,
+            user_properties,
+            keep: false,
+        })
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        match self {
+            Sink::V3(client) => client.disconnect().await?,
+            Sink::V5(client) => client.disconnect().await?,
+        }
+        Ok(())
+    }
+
+    pub async fn send(&self, msg: QueuedMsg) -> Result<()> {
+        let qos = rumqttc::qos(msg.qos)?;
+        match self {
+            Sink::V3(client) => client.publish(msg.topic, qos, msg.retain, msg.payload).await?,
+            Sink::V5(client) if msg.user_properties.is_empty() => {
+                client.publish(msg.topic, qos_v5(qos), msg.retain, msg.payload).await?
+            }
+            Sink::V5(client) => {
+                let properties = PublishProperties {
+                    user_properties: msg.user_properties,
+                    ..Default::default()
+                };
+                client.publish_with_properties(msg.topic, qos_v5(qos), msg.retain, msg.payload, properties).await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the message to the client without waiting for room in its request channel
+    pub fn try_send(&self, msg: QueuedMsg) -> Result<()> {
+        let qos = rumqttc::qos(msg.qos)?;
+        match self {
+            Sink::V3(client) => client.try_publish(msg.topic, qos, msg.retain, msg.payload)?,
+            Sink::V5(client) => client.try_publish(msg.topic, qos_v5(qos), msg.retain, msg.payload)?,
+        }
+        Ok(())
+    }
+}
+
+/// Acknowledges a message to the broker it came from when dropped, i.e. once every sink it was queued for accepted
+/// it, or for a downlink message from a sink once the source accepted it.
+///
+/// A message which a full queue dropped is acknowledged as well, within its session the source would not deliver
+/// it again and it would hold one of the inflight slots of the source until a reconnect.
+pub struct SourceAck {
+    client: AckClient,
+}
+
+/// The client and the publish to acknowledge, only the packet id and QoS of the publish are kept
+#[derive(Clone)]
+enum AckClient {
+    V3(AsyncClient, rumqttc::Publish),
+    V5(v5::AsyncClient, v5::mqttbytes::v5::Publish),
+}
+
+impl AckClient {
+    async fn ack(&self) -> Result<(), String> {
+        match self {
+            AckClient::V3(client, publish) => client.ack(publish).await.map_err(|e| e.to_string()),
+            AckClient::V5(client, publish) => client.ack(publish).await.map_err(|e| e.to_string()),
+        }
+    }
+
+    fn try_ack(&self) -> Result<(), String> {
+        match self {
+            AckClient::V3(client, publish) => client.try_ack(publish).map_err(|e| e.to_string()),
+            AckClient::V5(client, publish) => client.try_ack(publish).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            AckClient::V3(_, publish) => format!("{} on {}", publish.pkid, publish.topic),
+            AckClient::V5(_, publish) => format!("{} on {}", publish.pkid, String::from_utf8_lossy(&publish.topic)),
+        }
+    }
+}
+
+impl SourceAck {
+    /// Returns `None` for QoS 0 messages, which are never acknowledged
+    pub fn new(client: &AsyncClient, publish: &rumqttc::Publish) -> Option<Arc<Self>> {
+        if publish.qos == QoS::AtMostOnce {
+            return None;
+        }
+        let mut ack = rumqttc::Publish::new(&publish.topic, publish.qos, Vec::new());
+        ack.pkid = publish.pkid;
+        Some(Arc::new(SourceAck { client: AckClient::V3(client.clone(), ack) }))
+    }
+
+    /// Like `new` for a message from an MQTT v5 sink
+    pub fn new_v5(client: &v5::AsyncClient, publish: &v5::mqttbytes::v5::Publish) -> Option<Arc<Self>> {
+        if publish.qos == v5::mqttbytes::QoS::AtMostOnce {
+            return None;
+        }
+        let mut ack = v5::mqttbytes::v5::Publish::new("", publish.qos, Vec::new(), None);
+        ack.topic = publish.topic.clone();
+        ack.pkid = publish.pkid;
+        Some(Arc::new(SourceAck { client: AckClient::V5(client.clone(), ack) }))
+    }
+}
+
+impl Drop for SourceAck {
+    fn drop(&mut self) {
+        let client = self.client.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = client.ack().await {
+                        warn!("Could not acknowledge message {}: {e}", client.describe());
+                    }
+                });
+            }
+            Err(_) => {
+                if let Err(e) = client.try_ack() {
+                    warn!("Could not acknowledge message {}: {e}", client.describe());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct InflightState {
+    /// Acks of the publishes handed to the client, in order
+    sent: VecDeque<Option<Arc<SourceAck>>>,
+    /// Acks of the publishes waiting for the acknowledgement of the sink
+    published: HashMap<u16, Option<Arc<SourceAck>>>,
+}
+
+/// Matches the publishes to a sink with their acknowledgements.
+///
+/// The client reports every publish with its packet id in the order the requests were sent,
+/// so the source acks are kept in the same order until the packet id is known.
+#[derive(Default)]
+pub struct Inflight {
+    state: Mutex<InflightState>,
+    /// Held from `sending` until the client has the publish, so publishes of several tasks keep their acks in order
+    order: tokio::sync::Mutex<()>,
+}
+
+impl Inflight {
+    /// True once every publish handed to the client was acknowledged by the sink
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.sent.is_empty() && state.published.is_empty()
+    }
+
+    /// Called before the publish is handed to the client
+    fn sending(&self, ack: Option<Arc<SourceAck>>) {
+        self.state.lock().unwrap().sent.push_back(ack);
+    }
+
+    /// Publishes a queued message, `ack` is kept until the sink acknowledged it
+    pub async fn send(&self, sink: &Sink, msg: QueuedMsg, ack: Option<Arc<SourceAck>>) -> Result<()> {
+        let _order = self.order.lock().await;
+        self.sending(ack);
+        sink.send(msg).await.inspect_err(|_| {
+            self.state.lock().unwrap().sent.pop_back();
+        })
+    }
+
+    /// Hands a downlink message to a source without waiting, `ack` is kept until the source acknowledged it
+    pub fn try_publish(&self, client: &AsyncClient, topic: &str, qos: QoS, payload: Vec<u8>, ack: Option<Arc<SourceAck>>) -> Result<(), rumqttc::ClientError> {
+        // The state stays locked until the client has the publish, so publishes of several sinks keep their acks in order
+        let mut state = self.state.lock().unwrap();
+        state.sent.push_back(ack);
+        client.try_publish(topic, qos, false, payload).inspect_err(|_| {
+            state.sent.pop_back();
+        })
+    }
+
+    /// Publishes a message of the proxy itself right away, ahead of the queue of the sink
+    pub async fn send_now(&self, sink: &Sink, msg: QueuedMsg) -> Result<()> {
+        let _order = self.order.lock().await;
+        self.sending(None);
+        sink.try_send(msg).inspect_err(|_| {
+            self.state.lock().unwrap().sent.pop_back();
+        })
+    }
+
+    /// Called for every outgoing publish, QoS 0 publishes (packet id 0) are accepted once sent
+    pub fn published(&self, pkid: u16) {
+        let mut state = self.state.lock().unwrap();
+        if pkid != 0 && state.published.contains_key(&pkid) {
+            // Retransmission after a reconnect
+            return;
+        }
+        let ack = state.sent.pop_front().flatten();
+        if pkid != 0 {
+            state.published.insert(pkid, ack);
+        }
+    }
+
+    /// Called for PubAck and PubComp of the sink, or of the source for downlink messages
+    pub fn acknowledged(&self, pkid: u16) {
+        self.state.lock().unwrap().published.remove(&pkid);
+    }
+}
+
+/// A source the downlinks write to, its inflight keeps the acks of the downlink messages until the source accepted them
+#[derive(Clone)]
+pub struct DownlinkSource {
+    pub client: AsyncClient,
+    pub inflight: Arc<Inflight>,
+}
+
+/// The side of a sink the sources use, messages go through its queue
+#[derive(Clone)]
+pub struct QueuedSink {
+    pub sink: Sink,
+    pub queue: Arc<Queue>,
+}
+
+impl QueuedSink {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn push_labeled(&self, topic: String, qos: QoS, retain: bool, payload: &[u8], label: Label, label_key: &Key, ack: Option<Arc<SourceAck>>, sign_seconds: &Histogram) -> Result<()> {
+        let msg = sign_seconds.time(&[], || self.sink.prepare_labeled(topic, qos, retain, payload, label, label_key))?;
+        self.queue.push(msg, ack).await
+    }
+
+    /// Queues a message of the proxy itself like a label info, which is not dropped when the queue is full
+    pub async fn push(&self, topic: String, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<()> {
+        self.queue.push(QueuedMsg {
+            topic,
+            qos: qos as u8,
+            retain,
+            payload,
+            user_properties: Vec::new(),
+            keep: true,
+        }, None).await
+    }
+}
+
+/// Time until a queued message which the client did not take is published again
+const RESEND_DELAY: Duration = Duration::from_secs(1);
+
+/// Publishes the queued messages of a sink in order while it is connected
+pub async fn forward_task(name: String, queue: Arc<Queue>, sink: Sink, inflight: Arc<Inflight>, mut connected: watch::Receiver<bool>) -> Result<()> {
+    loop {
+        if !*connected.borrow() {
+            connected.wait_for(|connected| *connected).await?;
+            if queue.len() > 0 {
+                info!("Sink {name} connected, draining {} queued messages", queue.len());
+            }
+        }
+        let (seq, msg, ack) = queue.peek().await;
+        debug!("Publish queued message {seq} on {} to sink {name}", msg.topic);
+        // The message stays queued if the client did not take it, e.g. while the loop of the sink restarts
+        if let Err(e) = inflight.send(&sink, msg, ack).await {
+            warn!("Could not publish queued message {seq} to sink {name}: {e}");
+            tokio::time::sleep(RESEND_DELAY).await;
+            continue;
+        }
+        queue.pop(seq).await?;
+    }
+}
+
+/// Publishes the signed `online` status after every connect of a sink and heartbeats while it is connected.
+///
+/// The status bypasses the queue, behind a backlog it would arrive late or be dropped by the queue.
+pub async fn status_task(name: String, sink: Sink, inflight: Arc<Inflight>, status: Arc<StatusAnnouncer>, mut connected: watch::Receiver<bool>) -> Result<()> {
+    let period = Duration::from_secs(status.heartbeat_secs().max(1));
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        let state = tokio::select! {
+            changed = connected.changed() => {
+                changed?;
+                if !*connected.borrow_and_update() {
+                    continue;
+                }
+                heartbeat.reset();
+                State::Online
+            }
+            _ = heartbeat.tick(), if status.heartbeat_secs() > 0 => {
+                if !*connected.borrow() {
+                    continue;
+                }
+                State::Heartbeat
+            }
+        };
+        debug!("Status {state:?} to sink {name}");
+        let msg = QueuedMsg {
+            topic: status.topic().into(),
+            qos: QoS::AtLeastOnce as u8,
+            retain: true,
+            payload: status.message(state)?,
+            user_properties: Vec::new(),
+            keep: true,
+        };
+        if let Err(e) = inflight.send_now(&sink, msg).await {
+            warn!("Skipped the {state:?} status to sink {name}: {e}");
+        }
+    }
+}
+
+/// Runs the loop of a sink until it disconnects, a loop which failed is restarted so the other sources and sinks keep running
+#[allow(clippy::too_many_arguments)]
+pub async fn sink_task(
+    name: String,
+    mut eventloop: SinkEventLoop,
+    sink: Sink,
+    sources: HashMap<String, DownlinkSource>,
+    mut downlinks: Vec<Downlink>,
+    inflight: Arc<Inflight>,
+    connected: watch::Sender<bool>,
+    mut reconnect: Reconnect,
+    status: Option<Arc<StatusAnnouncer>>,
+    metrics: Arc<Metrics>,
+    mut shutdown: watch::Receiver<bool>) -> Result<()> {
+    loop {
+        let result = match (&mut eventloop, &sink) {
+            (SinkEventLoop::V3(eventloop), Sink::V3(sink)) => sink_loop_v3(&name, eventloop, sink, &sources, &mut downlinks, &inflight, &connected, &mut reconnect, status.as_deref()).await,
+            (SinkEventLoop::V5(eventloop), Sink::V5(sink)) => sink_loop_v5(&name, eventloop, sink, &sources, &mut downlinks, &inflight, &connected, &mut reconnect, status.as_deref()).await,
+            _ => return Err(eyre!("The event loop of sink {name} does not match its client")),
+        };
+        if let Err(e) = result {
+            if restart_site(&format!("Sink {name}"), &name, e, &mut reconnect, &connected, &metrics, &mut shutdown).await {
+                continue;
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sink_loop_v3(
+    name: &str,
+    eventloop: &mut rumqttc::EventLoop,
+    sink: &AsyncClient,
+    sources: &HashMap<String, DownlinkSource>,
+    downlinks: &mut [Downlink],
+    inflight: &Inflight,
+    connected: &watch::Sender<bool>,
+    reconnect: &mut Reconnect,
+    status: Option<&StatusAnnouncer>) -> Result<()> {
+    loop {
+        debug!("sink {name} loop");
+        match eventloop.poll().await {
+           Ok(notification) => {
+                match notification {
+                    Incoming(Publish(msg)) if msg.retain => {
+                        warn!("Rejected retained downlink message on {}", msg.topic);
+                        drop(SourceAck::new(sink, &msg));
+                    },
+                    Incoming(Publish(msg)) => {
+                        debug!("Downlink Incoming message from {name} = {:?}", msg);
+                        // Acknowledged to the sink once every source accepted it, or right away if it is rejected
+                        let ack = SourceAck::new(sink, &msg);
+                        for downlink in downlinks.iter_mut().filter(|downlink| downlink.matches(&msg.topic)) {
+                            match downlink.check(&msg.topic, &msg.payload) {
+                                Ok(Some(payload)) => {
+                                    let source = &sources[&downlink.source];
+                                    downlink.write_down(&source.client, &source.inflight, &msg.topic, msg.qos, payload, ack.clone());
+                                },
+                                Ok(None) => {},
+                                Err(e) => {
+                                    warn!("Rejected downlink message on {}: {e}", msg.topic);
+                                },
+                            }
+                        }
+                    },
+                    Incoming(ConnAck(_)) => {
+                        info!("reconnected sink {name}");
+                        reconnect.connected();
+                        connected.send_replace(true);
+                        for downlink in downlinks.iter() {
+                            sink.subscribe_many(downlink.filters()).await?;
+                        }
+                    },
+                    Incoming(PubAck(ack)) => inflight.acknowledged(ack.pkid),
+                    Incoming(PubComp(comp)) => inflight.acknowledged(comp.pkid),
+                    Outgoing(rumqttc::Outgoing::Publish(pkid)) => inflight.published(pkid),
+                    Outgoing(rumqttc::Outgoing::Disconnect) => {
+                        info!("Disconnected from sink {name}");
+                        return Ok(());
+                    },
+                    Incoming(incoming) => {
+                        debug!("Sink {name} Received Incoming event = {:?}", incoming);
+                    },
+                    Outgoing(outgoing) => {
+                        debug!("Sink {name} Received Outgoing event = {:?}", outgoing);
+                    },
+                };
+           }
+           Err(e) => {
+                connected.send_replace(false);
+                if !reconnect.error() {
+                    return Err(eyre!("Sink {name} connection exceeded the error budget of {}", reconnect.budget()));
+                }
+                // The broker publishes the Last Will with the datetime it was signed at
+                if let Some(status) = status {
+                    status.apply(&mut eventloop.mqtt_options)?;
+                }
+                delay_on_disconnect(e, reconnect).await;
+           }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sink_loop_v5(
+    name: &str,
+    eventloop: &mut v5::EventLoop,
+    sink: &v5::AsyncClient,
+    sources: &HashMap<String, DownlinkSource>,
+    downlinks: &mut [Downlink],
+    inflight: &Inflight,
+    connected: &watch::Sender<bool>,
+    reconnect: &mut Reconnect,
+    status: Option<&StatusAnnouncer>) -> Result<()> {
+    loop {
+        debug!("sink {name} loop");
+        match eventloop.poll().await {
+           Ok(v5::Event::Incoming(PacketV5::Publish(msg))) if msg.retain => {
+                warn!("Rejected retained downlink message on {}", String::from_utf8_lossy(&msg.topic));
+                drop(SourceAck::new_v5(sink, &msg));
+           }
+           Ok(v5::Event::Incoming(PacketV5::Publish(msg))) => {
+                debug!("Downlink Incoming message from {name} = {:?}", msg);
+                let ack = SourceAck::new_v5(sink, &msg);
+                let topic = String::from_utf8_lossy(&msg.topic).into_owned();
+                let properties = msg.properties.as_ref().map(|p| p.user_properties.as_slice()).unwrap_or_default();
+                for downlink in downlinks.iter_mut().filter(|downlink| downlink.matches(&topic)) {
+                    match downlink.check_properties(&topic, &msg.payload, properties) {
+                        Ok(Some(payload)) => {
+                            let source = &sources[&downlink.source];
+                            downlink.write_down(&source.client, &source.inflight, &topic, qos_v3(msg.qos), payload, ack.clone());
+                        },
+                        Ok(None) => {},
+                        Err(e) => {
+                            warn!("Rejected downlink message on {topic}: {e}");
+                        },
+                    }
+                }
+           }
+           Ok(v5::Event::Incoming(PacketV5::ConnAck(_))) => {
+                info!("reconnected sink {name}");
+                reconnect.connected();
+                connected.send_replace(true);
+                for downlink in downlinks.iter() {
+                    let filters = downlink.filters().into_iter().map(|f| v5::mqttbytes::v5::Filter::new(f.path, qos_v5(f.qos)));
+                    sink.subscribe_many(filters).await?;
+                }
+           }
+           Ok(v5::Event::Incoming(PacketV5::PubAck(ack))) => inflight.acknowledged(ack.pkid),
+           Ok(v5::Event::Incoming(PacketV5::PubComp(comp))) => inflight.acknowledged(comp.pkid),
+           Ok(v5::Event::Outgoing(rumqttc::Outgoing::Publish(pkid))) => inflight.published(pkid),
+           Ok(v5::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => {
+                info!("Disconnected from sink {name}");
+                return Ok(());
+           }
+           Ok(event) => {
+                debug!("Sink {name} Received event = {:?}", event);
+           }
+           Err(e) => {
+                connected.send_replace(false);
+                if !reconnect.error() {
+                    return Err(eyre!("Sink {name} connection exceeded the error budget of {}", reconnect.budget()));
+                }
+                if let Some(status) = status {
+                    status.apply_v5(&mut eventloop.options)?;
+                }
+                debug!("{:?}", e);
+                match e {
+                    v5::ConnectionError::Io(_)
+                    | v5::ConnectionError::Timeout(_)
+                    | v5::ConnectionError::ConnectionRefused(_)
+                    | v5::ConnectionError::Websocket(_) => reconnect.backoff().await,
+                    v5::ConnectionError::MqttState(_) => {}
+                    e => {
+                        error!("{:?}", e);
+                        reconnect.backoff().await;
+                    }
+                }
+           }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rumqttc::MqttOptions;
+
+    #[tokio::test]
+    async fn source_ack_after_sink_ack() {
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let publish = |qos| {
+            let mut publish = rumqttc::Publish::new("a", qos, vec![1]);
+            publish.pkid = 7;
+            publish
+        };
+        assert!(SourceAck::new(&client, &publish(QoS::AtMostOnce)).is_none());
+
+        let inflight = Inflight::default();
+        let first = SourceAck::new(&client, &publish(QoS::AtLeastOnce)).unwrap();
+        let second = SourceAck::new(&client, &publish(QoS::ExactlyOnce)).unwrap();
+        let (first_weak, second_weak) = (Arc::downgrade(&first), Arc::downgrade(&second));
+        inflight.sending(Some(first));
+        inflight.sending(None);
+        inflight.sending(Some(second));
+
+        inflight.published(3);
+        inflight.published(3);
+        inflight.published(0);
+        inflight.published(4);
+        assert!(first_weak.upgrade().is_some());
+        inflight.acknowledged(4);
+        assert!(second_weak.upgrade().is_none());
+        assert!(first_weak.upgrade().is_some());
+        inflight.acknowledged(3);
+        assert!(first_weak.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_send_rolled_back() {
+        let (sink_tx, sink_rx) = flume::unbounded();
+        let sink = Sink::V3(AsyncClient::from_senders(sink_tx));
+        let (source, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let mut publish = rumqttc::Publish::new("a", QoS::AtLeastOnce, vec![1]);
+        publish.pkid = 7;
+        let msg = QueuedMsg { topic: "a".into(), qos: 1, retain: false, payload: vec![1], user_properties: Vec::new(), keep: false };
+        let inflight = Inflight::default();
+        drop(sink_rx);
+        assert!(inflight.send(&sink, msg, SourceAck::new(&source, &publish)).await.is_err());
+        assert!(inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_acknowledged() {
+        let mut publish = rumqttc::Publish::new("a", QoS::AtLeastOnce, vec![1]);
+        publish.pkid = 7;
+        // With room for one request, a second one only fits if the ack was not sent
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let queue = Queue::open("fog", &crate::config::QueueConfig { max_messages: 1, overflow: crate::config::OverflowPolicy::DropNewest, ..Default::default() }).unwrap();
+        let msg = QueuedMsg { topic: "a".into(), qos: 1, retain: false, payload: vec![1], user_properties: Vec::new(), keep: false };
+        queue.push(msg.clone(), None).await.unwrap();
+        // A full queue drops the message and the source gets the ack right away
+        queue.push(msg, SourceAck::new(&client, &publish)).await.unwrap();
+        tokio::task::yield_now().await;
+        assert!(client.try_ack(&publish).is_err());
+
+        // Dropped outside of the runtime, e.g. by a queue dropped at exit
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 1);
+        let ack = SourceAck::new(&client, &publish).unwrap();
+        std::thread::spawn(move || drop(ack)).join().unwrap();
+        assert!(client.try_ack(&publish).is_err());
+    }
+
+    #[tokio::test]
+    async fn no_loss_across_restart() {
+        use ed25519_dalek::SigningKey;
+        use mls::{PublicKey, SignedMsg};
+        use rumqttc::Request;
+
+        let dir = std::env::temp_dir().join(format!("mls_no_loss_test_{}", std::process::id()));
+        let cfg = crate::config::QueueConfig { dir: Some(dir.clone()), ..Default::default() };
+        let (source_tx, source_rx) = flume::unbounded();
+        let source = AsyncClient::from_senders(source_tx);
+        let (sink_tx, sink_rx) = flume::unbounded();
+        let sink = Sink::V3(AsyncClient::from_senders(sink_tx));
+        let secret = SigningKey::from_bytes(&[5; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let key = Key::new(secret, "fog.label".into());
+        let sign_seconds = crate::metrics::Metrics::default().sign_seconds;
+        let from_source = |pkid: u16| {
+            let mut publish = rumqttc::Publish::new("sensors/a", QoS::AtLeastOnce, vec![pkid as u8]);
+            publish.pkid = pkid;
+            publish
+        };
+
+        let queued = QueuedSink { sink: sink.clone(), queue: Arc::new(Queue::open("fog", &cfg).unwrap()) };
+        for pkid in 1..=3 {
+            let publish = from_source(pkid);
+            let ack = SourceAck::new(&source, &publish);
+            queued.push_labeled(publish.topic.clone(), publish.qos, false, &publish.payload, 2, &key, ack, &sign_seconds).await.unwrap();
+        }
+        // The proxy stops before the sink accepted the messages, like a crash nothing is dropped
+        std::mem::forget(queued);
+        assert!(source_rx.is_empty());
+
+        // After the restart the persisted messages are published in order, then a new message from the source
+        let queued = QueuedSink { sink: sink.clone(), queue: Arc::new(Queue::open("fog", &cfg).unwrap()) };
+        assert_eq!(queued.queue.len(), 3);
+        let publish = from_source(4);
+        queued.push_labeled(publish.topic.clone(), publish.qos, false, &publish.payload, 2, &key, SourceAck::new(&source, &publish), &sign_seconds).await.unwrap();
+        let inflight = Arc::new(Inflight::default());
+        let (_connected_tx, connected) = watch::channel(true);
+        let forward = tokio::spawn(forward_task("fog".into(), queued.queue.clone(), sink, inflight.clone(), connected));
+        for (pkid, payload) in (10..14).zip(1..=4) {
+            let Request::Publish(publish) = sink_rx.recv_async().await.unwrap() else {
+                panic!("not a publish");
+            };
+            let signed: SignedMsg = ciborium::de::from_reader(&publish.payload[..]).unwrap();
+            assert_eq!(signed.verify_labeled(&public).unwrap(), (&[payload][..], 2));
+            inflight.published(pkid);
+        }
+        // The source gets the ack of the new message only once the sink acknowledged it
+        tokio::task::yield_now().await;
+        assert!(source_rx.is_empty());
+        for pkid in 10..14 {
+            inflight.acknowledged(pkid);
+        }
+        let ack = tokio::time::timeout(Duration::from_secs(5), source_rx.recv_async()).await.unwrap().unwrap();
+        assert!(matches!(&ack, Request::PubAck(ack) if ack.pkid == 4), "{ack:?}");
+        assert!(inflight.is_empty());
+        assert_eq!(queued.queue.len(), 0);
+        forward.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_while_connected() {
+        use ed25519_dalek::SigningKey;
+        use mls::{conf::{ConfKey, StatusConfig}, status::Status, PublicKey};
+        use rumqttc::Request;
+
+        let (sink_tx, sink_rx) = flume::unbounded();
+        let sink = Sink::V3(AsyncClient::from_senders(sink_tx));
+        let inflight = Arc::new(Inflight::default());
+        let cfg = StatusConfig {
+            topic: "mls/status/proxy".into(),
+            heartbeat_secs: 10,
+            key: ConfKey { path: "unused".into(), id: "status".into() },
+        };
+        let secret = SigningKey::from_bytes(&[4; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let status = Arc::new(StatusAnnouncer::new(&cfg, Key::new(secret, "status".into()), "proxy", "test".into(), vec!["info".into()]));
+        let (connected_tx, connected) = watch::channel(false);
+        tokio::spawn(status_task("fog".into(), sink, inflight.clone(), status, connected));
+
+        let next_state = || async {
+            let Request::Publish(publish) = sink_rx.recv_async().await.unwrap() else {
+                panic!("not a publish");
+            };
+            assert_eq!((publish.topic.as_str(), publish.retain), ("mls/status/proxy", true));
+            Status::verify(&publish.payload, &public).unwrap().state
+        };
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert!(sink_rx.is_empty());
+        connected_tx.send_replace(true);
+        assert_eq!(next_state().await, State::Online);
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(next_state().await, State::Heartbeat);
+        // The statuses take part in pairing the publishes with their acks
+        inflight.published(1);
+        inflight.published(2);
+        assert!(!inflight.is_empty());
+        inflight.acknowledged(1);
+        inflight.acknowledged(2);
+        assert!(inflight.is_empty());
+        connected_tx.send_replace(false);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(sink_rx.is_empty());
+    }
+}
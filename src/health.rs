@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::conf::HealthConfig;
+use crate::http::{self, Request, Response};
+
+/// Readiness of the parts of a service, like its broker connections, which is ready once every part is
+#[derive(Default)]
+pub struct Health {
+    parts: Mutex<BTreeMap<String, watch::Sender<bool>>>,
+    stopping: AtomicBool,
+}
+
+impl Health {
+    /// Registers a part which is not ready until `true` is sent
+    pub fn part(&self, name: &str) -> watch::Sender<bool> {
+        let (ready, _) = watch::channel(false);
+        self.parts.lock().unwrap().insert(name.into(), ready.clone());
+        ready
+    }
+
+    pub fn not_ready(&self) -> Vec<String> {
+        self.parts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, ready)| !*ready.borrow())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.stopping.load(Ordering::Relaxed) && self.not_ready().is_empty()
+    }
+
+    /// Reports the service as not ready anymore, also to systemd
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        if let Err(e) = sd_notify("STOPPING=1") {
+            warn!("Could not notify systemd: {e}");
+        }
+    }
+
+    async fn wait_ready(&self) {
+        while !self.is_ready() {
+            let parts: Vec<_> = self.parts.lock().unwrap().values().map(|ready| ready.subscribe()).collect();
+            for mut ready in parts {
+                let _ = ready.wait_for(|ready| *ready).await;
+            }
+        }
+    }
+
+    fn response(&self, path: &str) -> Response {
+        match path {
+            "/livez" => Response::ok("alive\n"),
+            "/readyz" if self.stopping.load(Ordering::Relaxed) => Response::new(503, "stopping\n"),
+            "/readyz" => match self.not_ready() {
+                parts if parts.is_empty() => Response::ok("ready\n"),
+                parts => Response::new(503, format!("not ready: {}\n", parts.join(", "))),
+            },
+            _ => Response::not_found(),
+        }
+    }
+}
+
+fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            datagram.send_to_addr(state.as_bytes(), &std::os::unix::net::SocketAddr::from_abstract_name(name)?)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets need Linux")),
+        None => datagram.send_to(state.as_bytes(), socket)?,
+    };
+    Ok(())
+}
+
+/// Sends a state like `READY=1` to systemd, does nothing unless it set `NOTIFY_SOCKET`
+pub fn sd_notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state),
+        None => Ok(()),
+    }
+}
+
+/// Tells systemd once the service is ready and keeps its watchdog fed
+async fn systemd_task(health: Arc<Health>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return std::future::pending().await;
+    }
+    health.wait_ready().await;
+    info!("Ready, notifying systemd");
+    if let Err(e) = sd_notify("READY=1") {
+        warn!("Could not notify systemd: {e}");
+    }
+    let Some(watchdog_usec) = std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) else {
+        return std::future::pending().await;
+    };
+    let mut watchdog = tokio::time::interval(Duration::from_micros(watchdog_usec / 2));
+    loop {
+        watchdog.tick().await;
+        if let Err(e) = sd_notify("WATCHDOG=1") {
+            warn!("Could not notify the systemd watchdog: {e}");
+        }
+    }
+}
+
+/// Serves `/livez` and `/readyz` and notifies systemd, runs until the HTTP endpoint fails
+pub async fn health_task(cfg: Option<HealthConfig>, health: Arc<Health>) -> io::Result<()> {
+    let systemd = tokio::spawn(systemd_task(health.clone()));
+    let result = match cfg {
+        Some(cfg) => {
+            info!("health endpoint = {}", cfg.listen);
+            http::serve(cfg.listen, move |req: Request| {
+                let health = health.clone();
+                async move { health.response(&req.path) }
+            })
+            .await
+        }
+        None => std::future::pending().await,
+    };
+    systemd.abort();
+    result
+}
+
+/// Asks the health endpoint at `listen` if the service is ready, for the `--healthcheck` of a container
+pub fn check(mut listen: SocketAddr, timeout: Duration) -> io::Result<bool> {
+    if listen.ip().is_unspecified() {
+        listen.set_ip(match listen {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let mut stream = TcpStream::connect_timeout(&listen, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    print!("{body}");
+    Ok(head.split_whitespace().nth(1) == Some("200"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readiness() {
+        let health = Arc::new(Health::default());
+        let broker = health.part("broker");
+        let socket = health.part("socket");
+        assert_eq!(health.response("/livez").status(), 200);
+        assert_eq!(health.response("/readyz").status(), 503);
+        assert_eq!(health.not_ready(), vec!["broker".to_string(), "socket".to_string()]);
+
+        let waiting = tokio::spawn({
+            let health = health.clone();
+            async move { health.wait_ready().await }
+        });
+        socket.send_replace(true);
+        broker.send_replace(true);
+        waiting.await.unwrap();
+        assert_eq!(health.response("/readyz").status(), 200);
+        broker.send_replace(false);
+        assert_eq!(health.not_ready(), vec!["broker".to_string()]);
+        broker.send_replace(true);
+        health.stop();
+        assert!(!health.is_ready());
+        assert_eq!(health.response("/readyz").status(), 503);
+    }
+
+    #[test]
+    fn notify() {
+        let path = std::env::temp_dir().join(format!("mls_notify_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn healthcheck() {
+        for (status, ready) in [("200 OK", true), ("503 Service Unavailable", false)] {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 256];
+                let len = stream.read(&mut request).unwrap();
+                assert!(request[..len].starts_with(b"GET /readyz HTTP/1.1\r\n"));
+                write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            });
+            assert_eq!(check(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()), Duration::from_secs(5)).unwrap(), ready);
+            server.join().unwrap();
+        }
+    }
+}
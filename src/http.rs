@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::{debug, error};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+/// Time a client has to send its request, so idle connections do not pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A parsed HTTP request.
+///
+/// Query parameters and parameters from a form or flat JSON body are merged into `params`.
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub params: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|v| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self::new(200, body)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404, "not found")
+    }
+
+    pub fn with_content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            408 => "Request Timeout",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        decoded.push(h << 4 | l);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            c => decoded.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_form(form: &str, params: &mut HashMap<String, String>) {
+    for pair in form.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(url_decode(key), url_decode(value));
+    }
+}
+
+fn parse_json(body: &[u8], params: &mut HashMap<String, String>) {
+    let Ok(serde_json::Value::Object(object)) = serde_json::from_slice(body) else {
+        return;
+    };
+    for (key, value) in object {
+        let value = match value {
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Null => continue,
+            other => other.to_string(),
+        };
+        params.insert(key, value);
+    }
+}
+
+/// Reads a line of at most `limit` bytes, a longer line is an error
+async fn read_line_limited(stream: &mut BufReader<TcpStream>, line: &mut String, limit: usize) -> io::Result<usize> {
+    let len = (&mut *stream).take(limit as u64).read_line(line).await?;
+    if len == limit && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "header too large"));
+    }
+    Ok(len)
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<Option<Request>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut line = String::new();
+    if read_line_limited(stream, &mut line, MAX_HEAD).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        ..Default::default()
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    request.path = url_decode(path);
+    parse_form(query, &mut request.params);
+
+    let mut head_len = line.len();
+    let mut content_length = 0;
+    let mut content_type = String::new();
+    loop {
+        line.clear();
+        if read_line_limited(stream, &mut line, MAX_HEAD - head_len).await? == 0 {
+            return Err(invalid("connection closed in the header"));
+        }
+        head_len += line.len();
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().map_err(|_| invalid("bad content length"))?,
+                "content-type" => content_type = value.to_ascii_lowercase(),
+                _ => {}
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(invalid("body too large"));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    if content_type.starts_with("application/json") {
+        parse_json(&body, &mut request.params);
+    } else if !body.is_empty() {
+        parse_form(&String::from_utf8_lossy(&body), &mut request.params);
+    }
+    Ok(Some(request))
+}
+
+async fn handle_connection<F, Fut>(stream: TcpStream, handler: F) -> io::Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let mut stream = BufReader::new(stream);
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(request))) => {
+            debug!("HTTP {} {}", request.method, request.path);
+            handler(request).await
+        }
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(e)) => Response::new(400, e.to_string()),
+        Err(_) => Response::new(408, "request timeout"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves HTTP on `addr`, answering one request per connection with `handler`.
+pub async fn serve<F, Fut>(addr: SocketAddr, handler: F) -> io::Result<()>
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+        let handler = handler.clone();
+        task::spawn(async move {
+            if let Err(e) = handle_connection(stream, handler).await {
+                error!("HTTP connection failed {e:?}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_params() {
+        let mut params = HashMap::new();
+        parse_form("topic=a%2Fb%2F%23&username=fog+user&acc=4&bad=%zz", &mut params);
+        assert_eq!(params["topic"], "a/b/#");
+        assert_eq!(params["username"], "fog user");
+        assert_eq!(params["acc"], "4");
+        assert_eq!(params["bad"], "%zz");
+        parse_json(br#"{"topic":"x/+","acc":1,"clientid":null}"#, &mut params);
+        assert_eq!(params["topic"], "x/+");
+        assert_eq!(params["acc"], "1");
+    }
+
+    #[tokio::test]
+    async fn request_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = BufReader::new(server);
+        client.write_all(b"GET /metrics?a=1 HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let request = read_request(&mut server).await.unwrap().unwrap();
+        assert_eq!((request.path.as_str(), request.param("a")), ("/metrics", Some("1")));
+        // A header line without end is not buffered beyond the limit
+        client.write_all(b"GET / HTTP/1.1\r\nX: ").await.unwrap();
+        client.write_all(&[b'x'; MAX_HEAD]).await.unwrap();
+        let e = read_request(&mut server).await.unwrap_err();
+        assert_eq!(e.to_string(), "header too large");
+    }
+
+    /// Collects the values of every `listen` key of a config
+    fn listen_addrs(value: &toml::Value, addrs: &mut Vec<String>) {
+        match value {
+            toml::Value::Table(table) => table.iter().for_each(|(key, value)| match value {
+                toml::Value::String(addr) if key == "listen" => addrs.push(addr.clone()),
+                value => listen_addrs(value, addrs),
+            }),
+            toml::Value::Array(values) => values.iter().for_each(|value| listen_addrs(value, addrs)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn container_listen_addrs() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/container/config");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let config: toml::Value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let mut addrs = Vec::new();
+            listen_addrs(&config, &mut addrs);
+            let mut distinct = addrs.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(distinct.len(), addrs.len(), "{} listens twice on an address: {addrs:?}", path.display());
+        }
+    }
+}
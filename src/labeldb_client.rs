@@ -0,0 +1,252 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::Semaphore;
+
+use crate::Label;
+use crate::topicdb::{DBResult, RequestError};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("socket error")]
+    Io(#[from] io::Error),
+    #[error("label_db did not answer in time")]
+    Timeout,
+    #[error("label_db closed the connection")]
+    Closed,
+    #[error("unexpected reply from label_db: {0}")]
+    Protocol(String),
+    #[error("request was denied: {0}")]
+    Denied(RequestError),
+}
+
+struct Connection {
+    stream: BufReader<UnixStream>,
+}
+
+impl Connection {
+    async fn connect(path: &Path) -> Result<Self, ClientError> {
+        let stream = UnixStream::connect(path).await?;
+        Ok(Connection {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    async fn send(&mut self, cmd: &str) -> Result<(), ClientError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, ClientError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(ClientError::Closed);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    async fn request_result(&mut self, cmd: &str) -> Result<DBResult, ClientError> {
+        self.send(cmd).await?;
+        let line = self.read_line().await?;
+        line.parse().map_err(|_| ClientError::Protocol(line))
+    }
+
+    async fn request_ok(&mut self, cmd: &str) -> Result<(), ClientError> {
+        self.send(cmd).await?;
+        let line = self.read_line().await?;
+        match line.parse() {
+            _ if line == "OK" => Ok(()),
+            Ok(DBResult::Denied(reason)) => Err(ClientError::Denied(reason)),
+            _ => Err(ClientError::Protocol(line)),
+        }
+    }
+
+    /// Reads `<value> <name>` lines until the closing `END` line.
+    async fn request_table<V: FromStr>(&mut self, cmd: &str) -> Result<Vec<(String, V)>, ClientError> {
+        self.send(cmd).await?;
+        let mut rows = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line == "END" {
+                break Ok(rows);
+            }
+            if let Ok(DBResult::Denied(reason)) = line.parse() {
+                break Err(ClientError::Denied(reason));
+            }
+            let Some((value, name)) = line.split_once(' ') else {
+                return Err(ClientError::Protocol(line));
+            };
+            let Ok(value) = value.parse() else {
+                return Err(ClientError::Protocol(line));
+            };
+            rows.push((name.to_string(), value));
+        }
+    }
+}
+
+/// A newline would end the request early and send the rest as another command
+fn single_line(topic: &str) -> Result<(), ClientError> {
+    if topic.contains('\n') {
+        return Err(ClientError::Denied(RequestError::InvalidTopic));
+    }
+    Ok(())
+}
+
+/// Async client for the line protocol `label_db` serves on its Unix socket.
+///
+/// Idle connections are kept in a pool and reused, a broken connection is replaced by a new one.
+#[derive(Clone)]
+pub struct LabelDbClient {
+    path: PathBuf,
+    timeout: Duration,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl LabelDbClient {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        LabelDbClient {
+            path: path.into(),
+            timeout: Duration::from_secs(5),
+            idle: Arc::new(Mutex::new(Vec::new())),
+            permits: Arc::new(Semaphore::new(4)),
+        }
+    }
+
+    /// Sets the maximum number of connections opened at the same time.
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.permits = Arc::new(Semaphore::new(size.max(1)));
+        self
+    }
+
+    /// Sets the time a single request may take including a reconnect.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn get(&self, topic: &str) -> Result<DBResult, ClientError> {
+        single_line(topic)?;
+        self.request(&format!("GET {topic}"), |conn, cmd| Box::pin(conn.request_result(cmd))).await
+    }
+
+    pub async fn list(&self, filter: &str) -> Result<Vec<(String, Label)>, ClientError> {
+        single_line(filter)?;
+        self.request(&format!("LIST {filter}"), |conn, cmd| Box::pin(conn.request_table(cmd))).await
+    }
+
+    /// Sets the label of a topic, replacing the label it had before.
+    ///
+    /// label_db denies it unless `allow_set` is enabled in its config.
+    pub async fn insert(&self, topic: &str, label: Label) -> Result<(), ClientError> {
+        single_line(topic)?;
+        self.request(&format!("SET {label} {topic}"), |conn, cmd| Box::pin(conn.request_ok(cmd))).await
+    }
+
+    /// Returns the counters label_db keeps about its database.
+    pub async fn stats(&self) -> Result<Vec<(String, u64)>, ClientError> {
+        self.request("STATS", |conn, cmd| Box::pin(conn.request_table(cmd))).await
+    }
+
+    /// Checks if `clearance` dominates every label the topic filter can match.
+    pub async fn check(&self, filter: &str, clearance: Label) -> Result<DBResult, ClientError> {
+        single_line(filter)?;
+        self.request(&format!("CHECK {clearance} {filter}"), |conn, cmd| Box::pin(conn.request_result(cmd))).await
+    }
+
+    async fn request<T, F>(&self, cmd: &str, exchange: F) -> Result<T, ClientError>
+    where
+        F: for<'c> Fn(&'c mut Connection, &'c str) -> Pin<Box<dyn Future<Output = Result<T, ClientError>> + Send + 'c>>,
+    {
+        // Waiting for a connection of a saturated pool counts towards the timeout
+        let attempt = async {
+            let _permit = self.permits.acquire().await.expect("the semaphore is never closed");
+            let pooled = self.idle.lock().expect("pool lock poisoned").pop();
+            if let Some(mut conn) = pooled {
+                match exchange(&mut conn, cmd).await {
+                    Ok(value) => {
+                        self.release(conn);
+                        return Ok(value);
+                    }
+                    Err(ClientError::Io(_) | ClientError::Closed) => {
+                        debug!("Pooled label_db connection is broken, reconnecting");
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let mut conn = Connection::connect(&self.path).await?;
+            let value = exchange(&mut conn, cmd).await?;
+            self.release(conn);
+            Ok(value)
+        };
+        tokio::time::timeout(self.timeout, attempt)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    fn release(&self, conn: Connection) {
+        self.idle.lock().expect("pool lock poisoned").push(conn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn reconnects_and_parses_replies() {
+        let path = std::env::temp_dir().join(format!("mls_client_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            // Every connection answers a single request and is closed afterwards
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let reply = match line.trim_end() {
+                    "GET a/b" => "3\n",
+                    "CHECK 1 a/#" => "Denied InsufficientClearance\n",
+                    "LIST a/#" => "3 a/b\n1 a/c d\nEND\n",
+                    "SET 2 a/b" => "OK\n",
+                    "SET 2 a/c" => "Denied Forbidden\n",
+                    _ => "Denied InvalidTopic\n",
+                };
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = LabelDbClient::new(&path).with_pool_size(1);
+        assert_eq!(client.get("a/b").await.unwrap(), DBResult::Some(3));
+        assert_eq!(client.check("a/#", 1).await.unwrap(), DBResult::Denied(RequestError::InsufficientClearance));
+        assert_eq!(client.list("a/#").await.unwrap(), vec![("a/b".to_string(), 3), ("a/c d".to_string(), 1)]);
+        assert!(matches!(client.list("#/a").await, Err(ClientError::Denied(RequestError::InvalidTopic))));
+        client.insert("a/b", 2).await.unwrap();
+        assert!(matches!(client.insert("a/c", 2).await, Err(ClientError::Denied(RequestError::Forbidden))));
+        assert!(matches!(client.get("a/b\nSET 0 a/b").await, Err(ClientError::Denied(RequestError::InvalidTopic))));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn saturated_pool_times_out() {
+        let client = LabelDbClient::new("/nonexistent").with_pool_size(1).with_timeout(Duration::from_secs(1));
+        let _busy = client.permits.acquire().await.unwrap();
+        assert!(matches!(client.get("a/b").await, Err(ClientError::Timeout)));
+    }
+}
@@ -5,40 +5,52 @@ use ed25519_dalek::{Signer, SigningKey, VerifyingKey, Signature, SignatureError}
 
 
 pub mod topicdb;
+pub mod labeldb_client;
+pub mod http;
+pub mod metrics;
+pub mod acl;
+pub mod audit;
+pub mod conf;
+pub mod health;
+pub mod lru;
+pub mod query;
+pub mod properties;
+pub mod reconnect;
+pub mod shutdown;
+pub mod status;
 
 pub type Label = u16;
+
+/// Checks if a `clearance` is allowed to read data labeled with `label`.
+///
+/// A higher value means more sensitive data, so a clearance dominates every label that is not higher than itself.
+pub fn dominates(clearance: Label, label: Label) -> bool {
+    clearance >= label
+}
+
+/// Checks if a `clearance` is allowed to write data labeled with `label`.
+///
+/// Writing to a lower label would leak data down, so only the label of the clearance itself may be written.
+pub fn may_write(clearance: Label, label: Label) -> bool {
+    clearance == label
+}
+
 #[derive(Error, Debug)]
 pub enum LabelError{
     #[error("serialization error")]
     Serialization(#[from] ciborium::ser::Error<std::io::Error>),
     #[error("deserialization error")]
     Deserialization(#[from] ciborium::de::Error<std::io::Error>),
+    #[error("signature verification failed")]
+    Signature(#[from] SignatureError),
+    #[error("missing or invalid user property {0}")]
+    Property(&'static str),
 }
 
-pub struct ErrorCounter{
-    count: usize,
-}
-
-impl ErrorCounter {
-    pub fn new() -> Self{
-        ErrorCounter{
-            count:0
-        }
-    }
-    pub fn inc(&mut self) {
-        self.count += 1;
-    }
-    pub fn reset(&mut self) {
-        self.count = 0;
-    }
-    pub fn is_too_mutch(&self) -> bool{
-        self.count > 40
-    }
-}
 pub struct PublicKey {
     pub_key: VerifyingKey,
 }
-impl <'sigmsg> PublicKey {
+impl PublicKey {
     pub fn new(pub_key: VerifyingKey) -> Self{
         PublicKey{
             pub_key,
@@ -54,7 +66,7 @@ pub struct Key {
 
 impl Key {
     pub fn new(secret: SigningKey, id: String) -> Self{
-        let id_len = id.as_bytes().len();
+        let id_len = id.len();
         Key{
             secret,
             id,
@@ -78,6 +90,10 @@ impl Key {
         }
     }
 
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
     pub fn sign(&self, data: Vec<u8>) -> SignedMsg{
         let ad = Vec::new();
         self.sign_with_ad(data, ad)
@@ -99,7 +115,7 @@ impl SignedMsg{
         buffer.extend_from_slice(&self.payload);
         buffer.extend_from_slice(&self.ad);
         buffer.extend_from_slice(&self.datetime.to_be_bytes());
-        buffer.extend_from_slice(&self.key_id.as_bytes());
+        buffer.extend_from_slice(self.key_id.as_bytes());
         let signature =  Signature::from_slice(&self.signature[..])?;
         key.pub_key.verify_strict(&buffer, &signature)?;
         Ok(&self.payload)
@@ -107,6 +123,60 @@ impl SignedMsg{
     pub fn get_key_id(&self) -> &str {
         &self.key_id
     }
+    pub fn get_ad(&self) -> &[u8] {
+        &self.ad
+    }
+    /// Unix time in seconds of the signature
+    pub fn get_datetime(&self) -> i64 {
+        self.datetime
+    }
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+    /// Verifies the signature and returns the payload together with the label from the additional data.
+    pub fn verify_labeled(&self, key: &PublicKey) -> Result<(&[u8], Label), LabelError> {
+        let (payload, ad) = self.verify_additional(key)?;
+        Ok((payload, ad.label))
+    }
+    /// Verifies the signature and returns the payload together with the additional data.
+    pub fn verify_additional(&self, key: &PublicKey) -> Result<(&[u8], AdditionalData), LabelError> {
+        let payload = self.verify(key)?;
+        let ad = AdditionalData::deserialize(&self.ad)?;
+        Ok((payload, ad))
+    }
+}
+
+/// The additional data the proxy signs together with every forwarded payload
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdditionalData {
+    pub label: Label,
+    /// The topic the message was published to, so the signature can not be replayed on another topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+impl AdditionalData {
+    pub fn new(label: Label) -> Self{
+        AdditionalData {
+            label,
+            topic: None,
+        }
+    }
+
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.into());
+        self
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, LabelError> {
+        let mut ad_bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut ad_bytes)?;
+        Ok(ad_bytes)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, LabelError> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
 }
 
 
@@ -129,6 +199,17 @@ impl LabeledInfo {
         }
     }
     
+    /// The topic under `mls_topic` where the retained info of `topic` is published
+    pub fn retained_topic(mls_topic: &str, topic: &str) -> String {
+        format!("{mls_topic}/{topic}")
+    }
+
+    /// Checks that a retained info received on `info_topic` describes the topic it is stored under,
+    /// so a signed info can not be replayed for another topic. Infos on `mls_topic` itself are accepted.
+    pub fn matches_info_topic(&self, mls_topic: &str, info_topic: &str) -> bool {
+        info_topic == mls_topic || info_topic == Self::retained_topic(mls_topic, &self.topic)
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, LabelError> {
         let mut label_info_bytes = Vec::new();
         ciborium::ser::into_writer(self, &mut label_info_bytes)?;
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map which forgets its least recently used entry once it holds `capacity` entries,
+/// so state kept per topic can not grow without bound
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by the tick of their last use, the first one is evicted
+    order: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &K) -> u64 {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.order.remove(used);
+            *used = self.tick;
+        }
+        self.order.insert(self.tick, key.clone());
+        self.tick
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() >= self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Marks `key` as used and returns its value, inserting `default()` first if it has none
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            self.evict();
+            self.entries.insert(key.clone(), (default(), 0));
+        }
+        self.touch(&key);
+        &mut self.entries.get_mut(&key).expect("inserted above").0
+    }
+
+    /// Marks `key` as used and sets its value, returns the value it had before
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some((current, _)) = self.entries.get_mut(&key) {
+            let previous = std::mem::replace(current, value);
+            self.touch(&key);
+            return Some(previous);
+        }
+        self.get_or_insert_with(key, || value);
+        None
+    }
+
+    /// The key which is evicted next
+    pub fn oldest(&self) -> Option<&K> {
+        self.order.first_key_value().map(|(_, key)| key)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.capacity
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        *lru.get_or_insert_with("a", || 0) += 1;
+        lru.get_or_insert_with("b", || 0);
+        // Using a again makes b the least recently used
+        *lru.get_or_insert_with("a", || 0) += 1;
+        lru.get_or_insert_with("c", || 0);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.get(&"a"), Some(&2));
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.oldest(), Some(&"a"));
+        assert!(lru.is_full());
+        assert_eq!(lru.remove(&"c"), Some(0));
+        assert_eq!(lru.iter().collect::<Vec<_>>(), vec![(&"a", &2)]);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::http::Response;
+
+/// Histogram buckets in seconds for signing and lookups, which take microseconds up to a busy database
+pub const LATENCY_BUCKETS: &[f64] = &[0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+trait Metric: Send + Sync {
+    fn render(&self, out: &mut String);
+}
+
+/// Values of one metric per combination of label values
+struct Family<T> {
+    name: String,
+    help: String,
+    kind: &'static str,
+    label_names: Vec<&'static str>,
+    values: Mutex<BTreeMap<Vec<String>, T>>,
+}
+
+impl<T: Default> Family<T> {
+    fn new(name: &str, help: &str, kind: &'static str, label_names: &[&'static str]) -> Self {
+        Family {
+            name: name.into(),
+            help: help.into(),
+            kind,
+            label_names: label_names.to_vec(),
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn update(&self, labels: &[&str], f: impl FnOnce(&mut T)) {
+        debug_assert_eq!(labels.len(), self.label_names.len(), "label values of {}", self.name);
+        let key = labels.iter().map(|label| label.to_string()).collect();
+        f(self.values.lock().unwrap().entry(key).or_default());
+    }
+
+    fn render(&self, out: &mut String, sample: impl Fn(&mut String, &str, &[String], &T)) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.kind);
+        for (labels, value) in self.values.lock().unwrap().iter() {
+            sample(out, &self.name, labels, value);
+        }
+    }
+
+    /// `{name="value",...}` with an optional extra label like the `le` of a bucket
+    fn labels(&self, values: &[String], extra: Option<(&str, &str)>) -> String {
+        let pairs: Vec<String> = self.label_names
+            .iter()
+            .copied()
+            .zip(values.iter().map(String::as_str))
+            .chain(extra)
+            .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+            .collect();
+        if pairs.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", pairs.join(","))
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub struct Counter(Family<u64>);
+
+impl Counter {
+    pub fn inc(&self, labels: &[&str]) {
+        self.0.update(labels, |value| *value += 1);
+    }
+
+    pub fn get(&self, labels: &[&str]) -> u64 {
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        self.0.values.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+
+    /// Stops exporting the value of `labels`, for label values like topics which are only tracked for a while
+    pub fn remove(&self, labels: &[&str]) {
+        let key: Vec<String> = labels.iter().map(|label| label.to_string()).collect();
+        self.0.values.lock().unwrap().remove(&key);
+    }
+}
+
+impl Metric for Counter {
+    fn render(&self, out: &mut String) {
+        self.0.render(out, |out, name, labels, value| {
+            let _ = writeln!(out, "{name}{} {value}", self.0.labels(labels, None));
+        });
+    }
+}
+
+pub struct Gauge(Family<f64>);
+
+impl Gauge {
+    pub fn set(&self, labels: &[&str], value: f64) {
+        self.0.update(labels, |current| *current = value);
+    }
+}
+
+impl Metric for Gauge {
+    fn render(&self, out: &mut String) {
+        self.0.render(out, |out, name, labels, value| {
+            let _ = writeln!(out, "{name}{} {value}", self.0.labels(labels, None));
+        });
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    family: Family<Buckets>,
+    bounds: Vec<f64>,
+}
+
+impl Histogram {
+    pub fn observe(&self, labels: &[&str], value: f64) {
+        self.family.update(labels, |buckets| {
+            buckets.counts.resize(self.bounds.len(), 0);
+            if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+                buckets.counts[bucket] += 1;
+            }
+            buckets.sum += value;
+            buckets.count += 1;
+        });
+    }
+
+    /// Observes the seconds since `start`
+    pub fn observe_since(&self, labels: &[&str], start: Instant) {
+        self.observe(labels, start.elapsed().as_secs_f64());
+    }
+
+    /// Observes the seconds `f` takes
+    pub fn time<T>(&self, labels: &[&str], f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.observe_since(labels, start);
+        result
+    }
+}
+
+impl Metric for Histogram {
+    fn render(&self, out: &mut String) {
+        self.family.render(out, |out, name, labels, buckets| {
+            let mut cumulative = 0;
+            for (bound, count) in self.bounds.iter().zip(&buckets.counts) {
+                cumulative += count;
+                let _ = writeln!(out, "{name}_bucket{} {cumulative}", self.family.labels(labels, Some(("le", &bound.to_string()))));
+            }
+            let _ = writeln!(out, "{name}_bucket{} {}", self.family.labels(labels, Some(("le", "+Inf"))), buckets.count);
+            let _ = writeln!(out, "{name}_sum{} {}", self.family.labels(labels, None), buckets.sum);
+            let _ = writeln!(out, "{name}_count{} {}", self.family.labels(labels, None), buckets.count);
+        });
+    }
+}
+
+/// The metrics of a service in the Prometheus text format
+#[derive(Default)]
+pub struct Registry {
+    metrics: Mutex<Vec<Arc<dyn Metric>>>,
+}
+
+impl Registry {
+    fn register<M: Metric + 'static>(&self, metric: M) -> Arc<M> {
+        let metric = Arc::new(metric);
+        self.metrics.lock().unwrap().push(metric.clone());
+        metric
+    }
+
+    pub fn counter(&self, name: &str, help: &str, labels: &[&'static str]) -> Arc<Counter> {
+        self.register(Counter(Family::new(name, help, "counter", labels)))
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[&'static str]) -> Arc<Gauge> {
+        self.register(Gauge(Family::new(name, help, "gauge", labels)))
+    }
+
+    /// `buckets` are the ascending upper bounds, the `+Inf` bucket is added
+    pub fn histogram(&self, name: &str, help: &str, labels: &[&'static str], buckets: &[f64]) -> Arc<Histogram> {
+        self.register(Histogram {
+            family: Family::new(name, help, "histogram", labels),
+            bounds: buckets.to_vec(),
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for metric in self.metrics.lock().unwrap().iter() {
+            metric.render(&mut out);
+        }
+        out
+    }
+
+    pub fn response(&self) -> Response {
+        Response::ok(self.render()).with_content_type(CONTENT_TYPE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        let registry = Registry::default();
+        let messages = registry.counter("mls_messages_total", "Messages", &["decision", "label"]);
+        let topics = registry.gauge("mls_topics", "Topics", &[]);
+        let latency = registry.histogram("mls_sign_seconds", "Signing", &[], &[0.001, 0.01]);
+        messages.inc(&["labeled", "2"]);
+        messages.inc(&["labeled", "2"]);
+        messages.inc(&["dropped", "say \"hi\""]);
+        messages.inc(&["forgotten", "1"]);
+        messages.remove(&["forgotten", "1"]);
+        topics.set(&[], 3.0);
+        latency.observe(&[], 0.0005);
+        latency.observe(&[], 0.005);
+        latency.observe(&[], 2.0);
+        assert_eq!(messages.get(&["labeled", "2"]), 2);
+        assert_eq!(registry.render(), "\
+# HELP mls_messages_total Messages
+# TYPE mls_messages_total counter
+mls_messages_total{decision=\"dropped\",label=\"say \\\"hi\\\"\"} 1
+mls_messages_total{decision=\"labeled\",label=\"2\"} 2
+# HELP mls_topics Topics
+# TYPE mls_topics gauge
+mls_topics 3
+# HELP mls_sign_seconds Signing
+# TYPE mls_sign_seconds histogram
+mls_sign_seconds_bucket{le=\"0.001\"} 1
+mls_sign_seconds_bucket{le=\"0.01\"} 2
+mls_sign_seconds_bucket{le=\"+Inf\"} 3
+mls_sign_seconds_sum 2.0055
+mls_sign_seconds_count 3
+");
+    }
+}
@@ -0,0 +1,178 @@
+//! Label transport in MQTT v5 user properties.
+//!
+//! The payload is forwarded unchanged and the fields of the `SignedMsg` envelope travel in
+//! user properties. The signature covers the same transcript as a `SignedMsg`, so a message
+//! can be converted between both transports without signing it again.
+
+use rumqttc::v5::mqttbytes::v5::Publish;
+
+use crate::{AdditionalData, Key, Label, LabelError, PublicKey, SignedMsg};
+
+pub const LABEL: &str = "mls-label";
+pub const KEY_ID: &str = "mls-key-id";
+pub const DATETIME: &str = "mls-datetime";
+pub const SIGNATURE: &str = "mls-signature";
+pub const TOPIC: &str = "mls-topic";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelProperties {
+    pub label: Label,
+    pub key_id: String,
+    pub datetime: i64,
+    pub signature: Vec<u8>,
+    /// The topic in the signed additional data, the signature is only valid for a publish on it
+    pub topic: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+impl LabelProperties {
+    pub fn to_user_properties(&self) -> Vec<(String, String)> {
+        vec![
+            (LABEL.into(), self.label.to_string()),
+            (KEY_ID.into(), self.key_id.clone()),
+            (DATETIME.into(), self.datetime.to_string()),
+            (SIGNATURE.into(), to_hex(&self.signature)),
+            (TOPIC.into(), self.topic.clone()),
+        ]
+    }
+
+    /// Reads the label fields from the user properties of a publish, other properties are ignored.
+    pub fn from_user_properties(properties: &[(String, String)]) -> Result<Self, LabelError> {
+        let get = |name: &'static str| {
+            properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+                .ok_or(LabelError::Property(name))
+        };
+        Ok(LabelProperties {
+            label: get(LABEL)?.parse().map_err(|_| LabelError::Property(LABEL))?,
+            key_id: get(KEY_ID)?.to_string(),
+            datetime: get(DATETIME)?.parse().map_err(|_| LabelError::Property(DATETIME))?,
+            signature: from_hex(get(SIGNATURE)?).ok_or(LabelError::Property(SIGNATURE))?,
+            topic: get(TOPIC)?.to_string(),
+        })
+    }
+
+    /// Rebuilds the `SignedMsg` which carries the same signature for `payload`
+    pub fn into_signed_msg(self, payload: Vec<u8>) -> Result<SignedMsg, LabelError> {
+        let ad = AdditionalData {
+            label: self.label,
+            topic: Some(self.topic),
+        };
+        Ok(SignedMsg {
+            payload,
+            ad: ad.serialize()?,
+            key_id: self.key_id,
+            datetime: self.datetime,
+            signature: self.signature,
+        })
+    }
+
+    /// Verifies the signature over `payload` published on `topic` and returns the label
+    pub fn verify(self, topic: &str, payload: &[u8], key: &PublicKey) -> Result<Label, LabelError> {
+        // Properties captured on another topic must not verify when replayed on this one
+        if self.topic != topic {
+            return Err(LabelError::Property(TOPIC));
+        }
+        let signed_msg = self.into_signed_msg(payload.to_vec())?;
+        let (_, label) = signed_msg.verify_labeled(key)?;
+        Ok(label)
+    }
+}
+
+impl Key {
+    /// Signs `payload` with its label and topic like `sign_with_ad` and returns the fields for the user properties
+    pub fn sign_properties(&self, payload: &[u8], label: Label, topic: &str) -> Result<LabelProperties, LabelError> {
+        let signed_msg = self.sign_with_ad(payload.to_vec(), AdditionalData::new(label).with_topic(topic).serialize()?);
+        Ok(LabelProperties {
+            label,
+            key_id: signed_msg.key_id,
+            datetime: signed_msg.datetime,
+            signature: signed_msg.signature,
+            topic: topic.into(),
+        })
+    }
+}
+
+/// Returns the key id a v5 publish claims to be signed with
+pub fn key_id(publish: &Publish) -> Option<&str> {
+    publish
+        .properties
+        .as_ref()?
+        .user_properties
+        .iter()
+        .find(|(key, _)| key == KEY_ID)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Verifies the label properties of a v5 publish against its unchanged payload and returns the label
+pub fn verify_publish(publish: &Publish, key: &PublicKey) -> Result<Label, LabelError> {
+    let properties = publish
+        .properties
+        .as_ref()
+        .map(|p| p.user_properties.as_slice())
+        .unwrap_or_default();
+    let topic = std::str::from_utf8(&publish.topic).map_err(|_| LabelError::Property(TOPIC))?;
+    LabelProperties::from_user_properties(properties)?.verify(topic, &publish.payload, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rumqttc::v5::mqttbytes::{v5::PublishProperties, QoS};
+
+    #[test]
+    fn properties_roundtrip() {
+        let secret = SigningKey::from_bytes(&[5; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let key = Key::new(secret, "proxy.label.1".into());
+
+        let properties = key.sign_properties(b"21.5", 3, "sensors/a/temp").unwrap();
+        let mut user_properties = vec![("other".to_string(), "x".to_string())];
+        user_properties.extend(properties.to_user_properties());
+        let mut publish = Publish::new("sensors/a/temp", QoS::AtLeastOnce, &b"21.5"[..], Some(PublishProperties {
+            user_properties,
+            ..Default::default()
+        }));
+        assert_eq!(key_id(&publish), Some("proxy.label.1"));
+        assert_eq!(verify_publish(&publish, &public).unwrap(), 3);
+
+        // The same signature is valid inside a SignedMsg envelope
+        let signed_msg = properties.clone().into_signed_msg(b"21.5".to_vec()).unwrap();
+        assert_eq!(signed_msg.verify_additional(&public).unwrap(), (&b"21.5"[..], AdditionalData::new(3).with_topic("sensors/a/temp")));
+        let mut other_topic = properties.clone();
+        other_topic.topic = "sensors/b/temp".into();
+        assert!(other_topic.verify("sensors/b/temp", b"21.5", &public).is_err());
+        assert!(matches!(properties.clone().verify("sensors/b/temp", b"21.5", &public), Err(LabelError::Property(TOPIC))));
+
+        // Valid properties replayed on another topic
+        let mut replayed = publish.clone();
+        replayed.topic = "sensors/b/temp".into();
+        assert!(matches!(verify_publish(&replayed, &public), Err(LabelError::Property(TOPIC))));
+        let mut unbound = publish.clone();
+        unbound.properties.as_mut().unwrap().user_properties.retain(|(key, _)| key != TOPIC);
+        assert!(matches!(verify_publish(&unbound, &public), Err(LabelError::Property(TOPIC))));
+
+        publish.payload = b"99.9"[..].into();
+        assert!(verify_publish(&publish, &public).is_err());
+        publish.properties = None;
+        assert!(matches!(verify_publish(&publish, &public), Err(LabelError::Property(LABEL))));
+        assert_eq!(from_hex(&to_hex(&properties.signature)), Some(properties.signature));
+        assert_eq!(from_hex("0g"), None);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{LabelError, PublicKey, SignedMsg};
+use crate::topicdb::DBResult;
+
+/// A label query published to the query topic of label_db.
+///
+/// The broker might only speak MQTT v3, so the reply topic and correlation data
+/// travel in the payload, over MQTT v5 they can be the response topic and correlation data properties.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelQuery {
+    pub topic: String,
+    pub reply_topic: String,
+    pub correlation: Vec<u8>,
+}
+
+impl LabelQuery {
+    pub fn new(topic: &str, reply_topic: &str, correlation: Vec<u8>) -> Self {
+        LabelQuery {
+            topic: topic.into(),
+            reply_topic: reply_topic.into(),
+            correlation,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, LabelError> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, LabelError> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
+}
+
+/// The query topic of label_db and the prefix of the reply topics.
+///
+/// The replies to a user are published below `<reply_prefix><user>`, only that user may read them, so the reply
+/// topic of a query names the requester whose clearance the answer is checked against.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryTopics {
+    /// Topic label queries are published on
+    pub topic: String,
+    /// Prefix of the reply topics ending with `/`, e.g. `mls/reply/`
+    pub reply_prefix: String,
+}
+
+impl QueryTopics {
+    /// The user a reply topic below `<reply_prefix><user>` belongs to
+    pub fn reply_owner<'t>(&self, reply_topic: &'t str) -> Option<&'t str> {
+        let user = reply_topic.strip_prefix(&self.reply_prefix)?.split('/').next()?;
+        (!user.is_empty() && !user.contains(['+', '#'])).then_some(user)
+    }
+
+    /// The filter of the reply topics of `user`
+    pub fn replies(&self, user: &str) -> String {
+        format!("{}{user}/#", self.reply_prefix)
+    }
+}
+
+/// The answer of label_db, it is sent inside a `SignedMsg` so other clients can not spoof it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LabelResponse {
+    pub topic: String,
+    pub result: DBResult,
+    pub correlation: Vec<u8>,
+}
+
+impl LabelResponse {
+    pub fn new(query: LabelQuery, result: DBResult) -> Self {
+        LabelResponse {
+            topic: query.topic,
+            result,
+            correlation: query.correlation,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, LabelError> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Verifies a signed response published by label_db and returns the contained answer.
+    pub fn from_signed(bytes: &[u8], key: &PublicKey) -> Result<Self, LabelError> {
+        let signed_msg: SignedMsg = ciborium::de::from_reader(bytes)?;
+        let payload = signed_msg.verify(key)?;
+        Ok(ciborium::de::from_reader(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Key;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn signed_response() {
+        let secret = SigningKey::from_bytes(&[7; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let key = Key::new(secret, "label_db.query.1".into());
+
+        let query = LabelQuery::new("a/b", "mls/reply/1", vec![1, 2, 3]);
+        let query = LabelQuery::deserialize(&query.serialize().unwrap()).unwrap();
+        let response = LabelResponse::new(query, DBResult::Some(2));
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&key.sign(response.serialize().unwrap()), &mut bytes).unwrap();
+
+        assert_eq!(LabelResponse::from_signed(&bytes, &public).unwrap(), response);
+        let other = PublicKey::new(SigningKey::from_bytes(&[8; 32]).verifying_key());
+        assert!(LabelResponse::from_signed(&bytes, &other).is_err());
+    }
+
+    #[test]
+    fn reply_owner() {
+        let topics = QueryTopics { topic: "mls/query".into(), reply_prefix: "mls/reply/".into() };
+        assert_eq!(topics.reply_owner("mls/reply/fog/1"), Some("fog"));
+        assert_eq!(topics.reply_owner("mls/reply/fog"), Some("fog"));
+        assert_eq!(topics.reply_owner("mls/reply/"), None);
+        assert_eq!(topics.reply_owner("mls/reply/+/1"), None);
+        assert_eq!(topics.reply_owner("mls/replies/fog"), None);
+        assert_eq!(topics.replies("fog"), "mls/reply/fog/#");
+    }
+}
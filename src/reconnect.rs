@@ -0,0 +1,139 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::conf::ReconnectConfig;
+use crate::metrics::Counter;
+
+/// Backoff between the reconnects of a broker connection and the budget of errors within a time window.
+pub struct Reconnect {
+    cfg: ReconnectConfig,
+    attempt: u32,
+    errors: VecDeque<Instant>,
+    was_connected: bool,
+    /// Counter of the reconnects and the broker label
+    metric: Option<(Arc<Counter>, String)>,
+}
+
+/// A random factor in `[1 - jitter, 1 + jitter]`, good enough to spread the reconnects of many clients
+fn jitter_factor(jitter: f64) -> f64 {
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+    1.0 + jitter.clamp(0.0, 1.0) * (2.0 * random - 1.0)
+}
+
+impl Reconnect {
+    pub fn new(cfg: ReconnectConfig) -> Self {
+        Reconnect {
+            cfg,
+            attempt: 0,
+            errors: VecDeque::new(),
+            was_connected: false,
+            metric: None,
+        }
+    }
+
+    /// Counts every connection after the first one in `reconnects`, labeled with `broker`
+    pub fn with_metric(mut self, reconnects: Arc<Counter>, broker: &str) -> Self {
+        self.metric = Some((reconnects, broker.into()));
+        self
+    }
+
+    /// Starts the backoff again from `initial_delay_ms`, the errors stay in the budget until they leave the window
+    pub fn connected(&mut self) {
+        self.attempt = 0;
+        if let Some((reconnects, broker)) = self.metric.as_ref().filter(|_| self.was_connected) {
+            reconnects.inc(&[broker]);
+        }
+        self.was_connected = true;
+    }
+
+    /// Records an error, returns false once more than `max_errors` happened within `error_window_secs`
+    pub fn error(&mut self) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.cfg.error_window_secs);
+        while self.errors.front().is_some_and(|error| now.duration_since(*error) > window) {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(now);
+        self.cfg.max_errors == 0 || self.errors.len() <= self.cfg.max_errors
+    }
+
+    /// The delay before the next reconnect, growing by `multiplier` up to `max_delay_ms`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.cfg.initial_delay_ms as f64 * self.cfg.multiplier.max(1.0).powi(self.attempt as i32);
+        self.attempt = self.attempt.saturating_add(1);
+        let delay = delay.min(self.cfg.max_delay_ms as f64) * jitter_factor(self.cfg.jitter);
+        Duration::from_millis(delay as u64)
+    }
+
+    /// Starts a new error budget for a connection which is restarted, returns the delay before the restart
+    pub fn restart(&mut self) -> Duration {
+        self.errors.clear();
+        let delay = self.cfg.max_delay_ms as f64 * jitter_factor(self.cfg.jitter);
+        Duration::from_millis(delay as u64)
+    }
+
+    pub async fn backoff(&mut self) {
+        tokio::time::sleep(self.next_delay()).await;
+    }
+
+    pub fn budget(&self) -> String {
+        format!("{} errors in {}s", self.cfg.max_errors, self.cfg.error_window_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(jitter: f64) -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            multiplier: 2.0,
+            jitter,
+            max_errors: 3,
+            error_window_secs: 10,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let reconnects = crate::metrics::Registry::default().counter("reconnects_total", "Reconnects", &["broker"]);
+        let mut reconnect = Reconnect::new(config(0.0)).with_metric(reconnects.clone(), "fog");
+        let delays: Vec<_> = (0..6).map(|_| reconnect.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        reconnect.connected();
+        assert_eq!(reconnect.next_delay().as_millis(), 100);
+        reconnect.connected();
+        assert_eq!(reconnects.get(&["fog"]), 1);
+
+        let mut reconnect = Reconnect::new(config(0.5));
+        for _ in 0..20 {
+            let delay = reconnect.next_delay().as_millis();
+            reconnect.connected();
+            assert!((50..=150).contains(&delay), "{delay}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn error_budget_window() {
+        let mut reconnect = Reconnect::new(config(0.0));
+        for _ in 0..3 {
+            assert!(reconnect.error());
+            tokio::time::advance(Duration::from_secs(4)).await;
+        }
+        // The first error left the window
+        assert!(reconnect.error());
+        assert!(!reconnect.error());
+        assert_eq!(reconnect.restart(), Duration::from_secs(1));
+        assert!(reconnect.error());
+
+        let mut unlimited = Reconnect::new(ReconnectConfig { max_errors: 0, ..config(0.0) });
+        assert!((0..100).all(|_| unlimited.error()));
+    }
+}
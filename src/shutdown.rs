@@ -0,0 +1,13 @@
+use std::io;
+
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+
+/// Waits for SIGINT or SIGTERM and returns the name of the signal
+pub async fn signal() -> io::Result<&'static str> {
+    let mut interrupt = unix_signal(SignalKind::interrupt())?;
+    let mut terminate = unix_signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
@@ -0,0 +1,138 @@
+use rumqttc::{v5, LastWill, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::conf::StatusConfig;
+use crate::{Key, LabelError, PublicKey, SignedMsg};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Online,
+    Heartbeat,
+    /// Published by the broker as Last Will, so it is signed when the connection is set up
+    Offline,
+}
+
+/// Liveness of a proxy or label_db, published retained on its status topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub state: State,
+    /// `proxy` or `label_db`
+    pub service: String,
+    pub client_id: String,
+    pub version: String,
+    /// Ids of the keys the service signs with
+    pub key_ids: Vec<String>,
+    /// Seconds until the next heartbeat, 0 if there are none
+    pub heartbeat_secs: u64,
+}
+
+impl Status {
+    pub fn serialize(&self) -> Result<Vec<u8>, LabelError> {
+        let mut status_bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut status_bytes)?;
+        Ok(status_bytes)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, LabelError> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
+
+    /// Checks the signature of a status message and decodes it
+    pub fn verify(msg: &[u8], key: &PublicKey) -> Result<Self, LabelError> {
+        let signed: SignedMsg = ciborium::de::from_reader(msg)?;
+        Self::deserialize(signed.verify(key)?)
+    }
+}
+
+/// Signs the status messages of one broker connection
+pub struct StatusAnnouncer {
+    topic: String,
+    heartbeat_secs: u64,
+    key: Key,
+    status: Status,
+}
+
+impl StatusAnnouncer {
+    /// `key` is the loaded `cfg.key`
+    pub fn new(cfg: &StatusConfig, key: Key, service: &str, client_id: String, key_ids: Vec<String>) -> Self {
+        StatusAnnouncer {
+            topic: cfg.topic.clone(),
+            heartbeat_secs: cfg.heartbeat_secs,
+            key,
+            status: Status {
+                state: State::Online,
+                service: service.into(),
+                client_id,
+                version: env!("CARGO_PKG_VERSION").into(),
+                key_ids,
+                heartbeat_secs: cfg.heartbeat_secs,
+            },
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn heartbeat_secs(&self) -> u64 {
+        self.heartbeat_secs
+    }
+
+    /// The signed CBOR `SignedMsg` of the status
+    pub fn message(&self, state: State) -> Result<Vec<u8>, LabelError> {
+        let status = Status { state, ..self.status.clone() };
+        let mut msg = Vec::new();
+        ciborium::ser::into_writer(&self.key.sign(status.serialize()?), &mut msg)?;
+        Ok(msg)
+    }
+
+    /// Registers the `offline` status as Last Will
+    pub fn apply(&self, options: &mut MqttOptions) -> Result<(), LabelError> {
+        options.set_last_will(LastWill::new(&self.topic, self.message(State::Offline)?, QoS::AtLeastOnce, true));
+        Ok(())
+    }
+
+    pub fn apply_v5(&self, options: &mut v5::MqttOptions) -> Result<(), LabelError> {
+        let will = v5::mqttbytes::v5::LastWill::new(&self.topic, self.message(State::Offline)?, v5::mqttbytes::QoS::AtLeastOnce, true, None);
+        options.set_last_will(will);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::ConfKey;
+    use ed25519_dalek::SigningKey;
+
+    #[test]
+    fn signed_status() {
+        let secret = SigningKey::from_bytes(&[7; 32]);
+        let public = PublicKey::new(secret.verifying_key());
+        let cfg = StatusConfig {
+            topic: "mls/status/proxy".into(),
+            heartbeat_secs: 60,
+            key: ConfKey { path: "unused".into(), id: "proxy.status.1".into() },
+        };
+        let announcer = StatusAnnouncer::new(&cfg, Key::new(secret, "proxy.status.1".into()), "proxy", "mls_proxy.1".into(), vec!["proxy.info.1".into()]);
+        let status = Status::verify(&announcer.message(State::Heartbeat).unwrap(), &public).unwrap();
+        assert_eq!(status, Status {
+            state: State::Heartbeat,
+            service: "proxy".into(),
+            client_id: "mls_proxy.1".into(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            key_ids: vec!["proxy.info.1".into()],
+            heartbeat_secs: 60,
+        });
+
+        let mut options = MqttOptions::new("mls_proxy.1", "localhost", 1883);
+        announcer.apply(&mut options).unwrap();
+        let will = options.last_will().unwrap();
+        assert_eq!((will.topic.as_str(), will.retain), ("mls/status/proxy", true));
+        assert_eq!(Status::verify(&will.message, &public).unwrap().state, State::Offline);
+
+        let other = PublicKey::new(SigningKey::from_bytes(&[8; 32]).verifying_key());
+        assert!(Status::verify(&will.message, &other).is_err());
+    }
+}
@@ -1,13 +1,16 @@
-use std::str::Split;
+use std::collections::HashMap;
+use std::str::{FromStr, Split};
 use std::convert::From;
+use std::fmt;
 use sequence_trie::SequenceTrie;
+use serde::{Deserialize, Serialize};
 use tokio::{sync::mpsc, task::JoinHandle};
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, watch};
 use tokio::sync::mpsc::error::SendError;
 use log::{debug, error};
 use thiserror::Error;
 
-use crate::Label;
+use crate::{dominates, Label};
 
 #[derive(Debug, Error)]
 pub enum DBError{
@@ -17,18 +20,86 @@ pub enum DBError{
     DatabaseChannel(#[from] mpsc::error::SendError<DBRequest>),
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum RequestError {
-    InvalidTopic
+    InvalidTopic,
+    InsufficientClearance,
+    /// A request with missing arguments or a label which is not a number
+    InvalidRequest,
+    /// A request label_db is not configured to allow, like `SET`
+    Forbidden,
 }
 
-#[derive(PartialEq, Debug)]
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTopic => write!(f, "InvalidTopic"),
+            Self::InsufficientClearance => write!(f, "InsufficientClearance"),
+            Self::InvalidRequest => write!(f, "InvalidRequest"),
+            Self::Forbidden => write!(f, "Forbidden"),
+        }
+    }
+}
+
+impl FromStr for RequestError {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "InvalidTopic" => Ok(Self::InvalidTopic),
+            "InsufficientClearance" => Ok(Self::InsufficientClearance),
+            "InvalidRequest" => Ok(Self::InvalidRequest),
+            "Forbidden" => Ok(Self::Forbidden),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum DBResult{
     None,
     Some(Label),
     Denied(RequestError),
 }
 
+impl DBResult {
+    /// The name of the variant, e.g. to count results
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Some(_) => "Some",
+            Self::Denied(_) => "Denied",
+        }
+    }
+}
+
+/// The line format used on the label_db socket: `None`, the label or `Denied <reason>`.
+impl fmt::Display for DBResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::Some(label) => write!(f, "{label}"),
+            Self::Denied(reason) => write!(f, "Denied {reason}"),
+        }
+    }
+}
+
+impl FromStr for DBResult {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "None" => Ok(Self::None),
+            // Older label_db versions replied with a bare `Denied`
+            "Denied" => Ok(Self::Denied(RequestError::InvalidTopic)),
+            _ => match s.strip_prefix("Denied ") {
+                Some(reason) => Ok(Self::Denied(reason.parse()?)),
+                None => s.parse::<Label>().map(Self::Some).map_err(|_| ()),
+            },
+        }
+    }
+}
+
 impl From<Option<Label>> for DBResult {
     fn from(opt: Option<Label>) -> Self {
         match opt {
@@ -84,6 +155,12 @@ pub struct TopicDB {
     trie: SequenceTrie<String, Label>,
 }
 
+impl Default for TopicDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'s> TopicDB {
     pub fn new() -> Self{
         Self{
@@ -96,7 +173,7 @@ impl<'s> TopicDB {
     }
 
     fn get_min(sub_trie: &&SequenceTrie<String, Label>) -> Option<Label>{
-            return sub_trie.values().min().copied()
+            sub_trie.values().min().copied()
     }
 
     fn get_value(sub_trie: &&SequenceTrie<String, Label>) -> Option<Label>{
@@ -108,6 +185,15 @@ impl<'s> TopicDB {
         self.trie.insert(keys, label)
     }
 
+    /// Number of labeled topics
+    pub fn len(&self) -> usize {
+        self.trie.values().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trie.is_empty()
+    }
+
     pub fn get(&'s self, topic: &str) -> DBResult {
         if topic == "#" {
             return self.trie.values().min().copied().into()
@@ -131,7 +217,7 @@ impl<'s> TopicDB {
                 if sub_key == ["+"] {
                     new_nodes.append(n.children().as_mut());
                 } else {
-                    match n.get_node(sub_key.iter().map(|x| *x)) {
+                    match n.get_node(sub_key.iter().copied()) {
                         None => {},
                         Some(node) => {
                             new_nodes.push(node);
@@ -153,41 +239,182 @@ impl<'s> TopicDB {
 
         min_label.into()
     }
+
+    /// Finds the label of the stored topic filter that matches the topic name `topic` most specifically.
+    ///
+    /// Filters are compared level by level from the left: an exact level wins over `+`, which wins over `#`.
+    pub fn lookup(&self, topic: &str) -> Option<Label> {
+        let levels: Vec<&str> = Self::split_topic(topic).collect();
+        Self::lookup_levels(&self.trie, &levels)
+    }
+
+    fn lookup_levels(node: &SequenceTrie<String, Label>, levels: &[&str]) -> Option<Label> {
+        let multi_level = || node.get_node(["#"]).and_then(|n| n.value().copied());
+        let Some((level, rest)) = levels.split_first() else {
+            // `a/#` also matches the parent level `a`
+            return node.value().copied().or_else(multi_level);
+        };
+        node.get_node([*level])
+            .and_then(|n| Self::lookup_levels(n, rest))
+            .or_else(|| node.get_node(["+"]).and_then(|n| Self::lookup_levels(n, rest)))
+            .or_else(multi_level)
+    }
+
+    /// Returns every stored topic matched by `filter` together with its label, sorted by topic.
+    pub fn list(&self, filter: &str) -> Result<Vec<(String, Label)>, RequestError> {
+        validate_filter(filter)?;
+        let mut topics: Vec<(String, Label)> = self.trie
+            .iter()
+            .map(|(keys, label)| (keys.iter().map(|k| k.as_str()).collect::<Vec<_>>().join("/"), *label))
+            .filter(|(topic, _)| matches(filter, topic))
+            .collect();
+        topics.sort();
+        Ok(topics)
+    }
+
+    /// Checks if `clearance` dominates the label of every topic matched by `filter`.
+    ///
+    /// On success the highest matched label is returned.
+    pub fn check(&self, filter: &str, clearance: Label) -> DBResult {
+        let labels = match self.list(filter) {
+            Ok(labels) => labels,
+            Err(e) => return DBResult::Denied(e),
+        };
+        match labels.into_iter().map(|(_, label)| label).max() {
+            None => DBResult::None,
+            Some(label) if dominates(clearance, label) => DBResult::Some(label),
+            Some(_) => DBResult::Denied(RequestError::InsufficientClearance),
+        }
+    }
+}
+
+/// Checks if `filter` is a valid MQTT topic filter.
+pub fn is_valid_filter(filter: &str) -> bool {
+    validate_filter(filter).is_ok()
+}
+
+fn validate_filter(filter: &str) -> Result<(), RequestError> {
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let is_last = levels.peek().is_none();
+        if (level.contains('#') && (level != "#" || !is_last)) || (level.contains('+') && level != "+") {
+            return Err(RequestError::InvalidTopic);
+        }
+    }
+    Ok(())
+}
+
+/// Checks if the MQTT topic filter `filter` matches the topic name `topic`.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {},
+            (level, Some(topic_level)) if level == topic_level => {},
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Checks if some topic name matches both MQTT topic filters.
+pub fn overlaps(a: &str, b: &str) -> bool {
+    let (mut a_levels, mut b_levels) = (a.split('/'), b.split('/'));
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (None, None) => return true,
+            (Some(a), Some(b)) if a == b || a == "+" || b == "+" => {},
+            _ => return false,
+        }
+    }
 }
 
 
 #[derive(Debug)]
 pub enum DBRequest{
     Get(String, oneshot::Sender<DBResult>),
-    Insert(String, Label)
+    List(String, oneshot::Sender<Result<Vec<(String, Label)>, RequestError>>),
+    Check(String, Label, oneshot::Sender<DBResult>),
+    Stats(oneshot::Sender<DBStats>),
+    Insert(String, Label),
+    /// The label of a signed info with its datetime, replies if it was inserted
+    InsertInfo(String, Label, i64, oneshot::Sender<bool>),
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DBStats {
+    /// Number of labeled topics
+    pub topics: usize,
+    /// Number of inserts since the start
+    pub inserts: u64,
+    /// Number of inserts which added a topic or changed its label
+    pub changes: u64,
+}
+
+fn reply<T: fmt::Debug>(reply_channel: oneshot::Sender<T>, result: T) {
+    match reply_channel.send(result){
+        Ok(()) => {
+            debug!("Send reply back to requester")
+        },
+        Err(e) => {
+            error!("Was not able to reply on one shoot channel {e:?}")
+        },
+    };
+}
+
+fn insert(database: &mut TopicDB, stats: &mut DBStats, changes: &watch::Sender<u64>, topic: &str, label: Label) {
+    stats.inserts += 1;
+    if database.insert(topic, label) != Some(label) {
+        stats.changes += 1;
+        changes.send_replace(stats.changes);
+    }
 }
 
 #[derive(Clone)]
 pub struct Database {
     tx: mpsc::Sender<DBRequest>,
+    changes: watch::Receiver<u64>,
 }
 
 impl Database {
     pub fn new() -> (Database, JoinHandle<()>){
         let (tx, mut rx) = mpsc::channel::<DBRequest>(3200);
+        let (changes_tx, changes) = watch::channel(0);
         let handle = tokio::spawn(async move{
             let mut database = TopicDB::new();
+            let mut stats = DBStats::default();
+            // Datetime of the newest info of every topic
+            let mut info_datetimes: HashMap<String, i64> = HashMap::new();
             loop {
                 let msg = rx.recv().await;  
                 match msg {
-                    Some(DBRequest::Insert(topic, label)) => {
-                        database.insert(&topic, label);
-                    },
+                    Some(DBRequest::Insert(topic, label)) => insert(&mut database, &mut stats, &changes_tx, &topic, label),
+                    Some(DBRequest::InsertInfo(topic, label, datetime, reply_channel)) => {
+                        let newest = info_datetimes.entry(topic.clone()).or_insert(datetime);
+                        let accepted = datetime >= *newest;
+                        if accepted {
+                            *newest = datetime;
+                            insert(&mut database, &mut stats, &changes_tx, &topic, label);
+                        }
+                        reply(reply_channel, accepted);
+                    }
                     Some(DBRequest::Get(topic, reply_channel)) => {
                         let result = database.get(&topic);
-                        match reply_channel.send(result){
-                            Ok(()) => {
-                                debug!("Send reply back to requester")
-                            },
-                            Err(e) => {
-                                error!("Was not able to reply on one shoot channel {e:?}")
-                            },
-                        };
+                        reply(reply_channel, result);
+                    }
+                    Some(DBRequest::List(filter, reply_channel)) => {
+                        let result = database.list(&filter);
+                        reply(reply_channel, result);
+                    }
+                    Some(DBRequest::Check(filter, clearance, reply_channel)) => {
+                        let result = database.check(&filter, clearance);
+                        reply(reply_channel, result);
+                    }
+                    Some(DBRequest::Stats(reply_channel)) => {
+                        stats.topics = database.len();
+                        reply(reply_channel, stats);
                     }
                     None => {
                         dbg!("none");
@@ -196,14 +423,27 @@ impl Database {
             }
         });
         let db = Database {
-            tx
+            tx,
+            changes,
         };
         (db, handle)
     }
+    /// Returns a receiver which is notified whenever a topic is added or its label changes.
+    pub fn changes(&self) -> watch::Receiver<u64> {
+        self.changes.clone()
+    }
     pub async fn insert(&self, topic:String,  label:Label) -> Result<(), SendError<DBRequest>>{
         let msg = DBRequest::Insert(topic, label);
         self.tx.send(msg).await
     }
+    /// Inserts the label of a signed info unless the topic has a newer info, returns false for an older one.
+    ///
+    /// An info with the same datetime is accepted, a retained info is delivered again on every reconnect.
+    pub async fn insert_info(&self, topic: String, label: Label, datetime: i64) -> Result<bool, DBError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBRequest::InsertInfo(topic, label, datetime, tx)).await?;
+        Ok(rx.await?)
+    }
     pub async fn get(&self, topic:String) -> Result<DBResult, DBError>{
         let (tx, rx) = oneshot::channel::<DBResult>();
         let msg = DBRequest::Get(topic, tx);
@@ -211,11 +451,41 @@ impl Database {
         let label = rx.await?;
         Ok(label)
     }
+    pub async fn list(&self, filter:String) -> Result<Result<Vec<(String, Label)>, RequestError>, DBError>{
+        let (tx, rx) = oneshot::channel();
+        let msg = DBRequest::List(filter, tx);
+        self.tx.send(msg).await?;
+        Ok(rx.await?)
+    }
+    pub async fn check(&self, filter:String, clearance:Label) -> Result<DBResult, DBError>{
+        let (tx, rx) = oneshot::channel::<DBResult>();
+        let msg = DBRequest::Check(filter, clearance, tx);
+        self.tx.send(msg).await?;
+        Ok(rx.await?)
+    }
+    pub async fn stats(&self) -> Result<DBStats, DBError>{
+        let (tx, rx) = oneshot::channel::<DBStats>();
+        self.tx.send(DBRequest::Stats(tx)).await?;
+        Ok(rx.await?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[tokio::test]
+    async fn newest_info() {
+        let (db, _handle) = Database::new();
+        assert!(db.insert_info("a".into(), 2, 10).await.unwrap());
+        assert!(!db.insert_info("a".into(), 1, 5).await.unwrap());
+        assert_eq!(db.get("a".into()).await.unwrap(), DBResult::Some(2));
+        // A retained info delivered again has the same datetime
+        assert!(db.insert_info("a".into(), 2, 10).await.unwrap());
+        assert!(db.insert_info("a".into(), 1, 11).await.unwrap());
+        assert!(db.insert_info("b".into(), 3, 1).await.unwrap());
+        assert_eq!(db.get("a".into()).await.unwrap(), DBResult::Some(1));
+        assert_eq!(db.stats().await.unwrap().changes, 3);
+    }
     #[test]
     fn insert() {
         let mut db = TopicDB::new();
@@ -278,4 +548,62 @@ mod tests {
 
         assert_eq!(db.get("in/2/+/test"), DBResult::Some(6));
     }
+    #[test]
+    fn list() {
+        let mut db = TopicDB::new();
+        db.insert("in/test", 5);
+        db.insert("in/abc", 4);
+        db.insert("in/test/abc", 9);
+        db.insert("out/abc", 1);
+
+        assert_eq!(db.list("in/+").unwrap(), vec![("in/abc".to_string(), 4), ("in/test".to_string(), 5)]);
+        assert_eq!(db.list("#").unwrap().len(), db.len());
+        assert_eq!(db.list("in/#/abc"), Err(RequestError::InvalidTopic));
+    }
+    #[test]
+    fn check() {
+        let mut db = TopicDB::new();
+        db.insert("in/test", 5);
+        db.insert("in/abc", 4);
+        db.insert("out/abc", 1);
+
+        assert_eq!(db.check("in/#", 5), DBResult::Some(5));
+        assert_eq!(db.check("in/#", 4), DBResult::Denied(RequestError::InsufficientClearance));
+        assert_eq!(db.check("in/abc", 4), DBResult::Some(4));
+        assert_eq!(db.check("nothing/#", 0), DBResult::None);
+    }
+    #[test]
+    fn lookup_precedence() {
+        let mut rules = TopicDB::new();
+        rules.insert("sensors/#", 2);
+        rules.insert("sensors/+/temp", 3);
+        rules.insert("sensors/kitchen/temp", 1);
+        rules.insert("+/kitchen/#", 4);
+
+        assert_eq!(rules.lookup("sensors/kitchen/temp"), Some(1));
+        assert_eq!(rules.lookup("sensors/garage/temp"), Some(3));
+        assert_eq!(rules.lookup("sensors/kitchen/humidity"), Some(2));
+        assert_eq!(rules.lookup("sensors"), Some(2));
+        assert_eq!(rules.lookup("lights/kitchen/1"), Some(4));
+        assert_eq!(rules.lookup("lights/garage"), None);
+        assert!(!is_valid_filter("sensors/#/temp"));
+    }
+    #[test]
+    fn filter_matches() {
+        assert!(matches("#", "a/b"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/+/c", "a/b/c"));
+        assert!(!matches("a/+", "a/b/c"));
+        assert!(!matches("a/b", "a"));
+        assert!(overlaps("a/+/c", "a/b/#"));
+        assert!(overlaps("+/+", "a/b") && overlaps("a", "a/#"));
+        assert!(!overlaps("a/+", "a/b/c") && !overlaps("a/b", "a/c/#"));
+    }
+    #[test]
+    fn result_line_format() {
+        for result in [DBResult::None, DBResult::Some(7), DBResult::Denied(RequestError::InsufficientClearance)] {
+            assert_eq!(result.to_string().parse(), Ok(result));
+        }
+        assert_eq!("Denied".parse(), Ok(DBResult::Denied(RequestError::InvalidTopic)));
+    }
 }